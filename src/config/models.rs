@@ -1,6 +1,7 @@
 // src/config/models.rs
 use serde::{Deserialize, Serialize};
-use crate::session::SessionMode;
+use crate::matching::{CaptureRule, DynamicValueRule, MatchConfig};
+use crate::session::{default_journal_limit, default_max_body_bytes, ReplayConfig, SessionMode};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
@@ -10,6 +11,29 @@ pub struct AppConfig {
     pub auto_generate_sessions: bool,
     #[serde(default)]
     pub proxy: ProxyConfig, // Add the proxy configuration field
+    // Matching criteria given to newly created sessions
+    #[serde(default)]
+    pub matching: MatchConfig,
+    // Replay behavior given to newly created sessions
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    // Dynamic-value rules given to newly created sessions
+    #[serde(default)]
+    pub dynamic_values: Vec<DynamicValueRule>,
+    // Capture rules given to newly created sessions
+    #[serde(default)]
+    pub captures: Vec<CaptureRule>,
+    // Requests each session keeps in its journal
+    #[serde(default = "default_journal_limit")]
+    pub journal_limit: usize,
+    // Largest body each session buffers or records
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub expiry: SessionExpiryConfig,
+    // Ways to tell which session a request belongs to, tried in order
+    #[serde(default = "default_session_extractors")]
+    pub session_extractors: Vec<SessionExtractor>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,23 +46,149 @@ pub struct ServerConfig {
 pub struct StorageConfig {
     pub type_: String,
     pub path: String,
+    // Headers that are never persisted with an interaction
+    #[serde(default)]
+    pub header_deny_list: Vec<String>,
+    // Recorded bodies past this size wait in a temporary file while they stream,
+    // then the filesystem storage keeps them as body files of the session. The
+    // memory storage reads them back whole and keeps them inline
+    #[serde(default = "default_spill_threshold_bytes")]
+    pub spill_threshold_bytes: usize,
+    // Directory stub body files are read from, stubs cannot name files outside it
+    #[serde(default = "default_body_files_path")]
+    pub body_files_path: String,
 }
 
 // New struct for proxy configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyConfig {
+    // Mode given to newly created sessions
+    #[serde(default = "default_proxy_mode")]
+    pub default_mode: SessionMode,
     #[serde(default)]
     pub default_target: String,
-    #[serde(default = "default_as_true")]
+    // Send the client's Host header upstream instead of the target's authority
+    #[serde(default = "default_as_false")]
     pub forward_host_header: bool,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    #[serde(default = "default_pool_idle_timeout_secs")]
+    pub pool_idle_timeout_secs: u64,
+    // Negotiate HTTP/2 through ALPN when the upstream supports it
+    #[serde(default = "default_as_false")]
+    pub http2: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+}
+
+// Where the session ID of a request is read from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionExtractor {
+    // A request header, removed before forwarding
+    Header { name: String },
+    // A query parameter, removed before forwarding
+    Query { name: String },
+    // A cookie, removed before forwarding
+    Cookie { name: String },
+    // The path segment after a prefix such as /s, e.g. /s/{session}/users,
+    // the prefix and the session are stripped before forwarding
+    PathPrefix { prefix: String },
+    // A claim of a JWT, the signature is not verified
+    JwtClaim {
+        claim: String,
+        #[serde(default = "default_jwt_header")]
+        header: String,
+    },
+    // The address of the connecting client
+    ClientIp,
+}
+
+// Eviction of sessions nobody used for a while
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionExpiryConfig {
+    // Idle time after which a session is evicted, 0 keeps sessions forever
+    #[serde(default)]
+    pub idle_ttl_secs: u64,
+    #[serde(default = "default_sweep_interval_secs")]
+    pub sweep_interval_secs: u64,
+    // Also remove the evicted session's recordings, stubs and configuration.
+    // Otherwise the session comes back as it was on its next request
+    #[serde(default = "default_as_false")]
+    pub cleanup_storage: bool,
+}
+
+// Root certificate store used to verify upstream servers
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsRootStore {
+    System,
+    Webpki,
+    Custom,
+}
+
+// TLS settings for connections to upstream targets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_root_store")]
+    pub root_store: TlsRootStore,
+    // PEM bundle with the trusted CAs, required by the custom root store
+    #[serde(default)]
+    pub ca_bundle_path: Option<String>,
+    // Accept any upstream certificate, only meant for self-signed staging hosts
+    #[serde(default = "default_as_false")]
+    pub insecure_skip_verify: bool,
 }
 
 fn default_as_false() -> bool {
     false
 }
 
-fn default_as_true() -> bool {
-    true
+fn default_pool_max_idle_per_host() -> usize {
+    32
+}
+
+fn default_pool_idle_timeout_secs() -> u64 {
+    90
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_read_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_spill_threshold_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_body_files_path() -> String {
+    "./stubs".to_string()
+}
+
+fn default_jwt_header() -> String {
+    "Authorization".to_string()
+}
+
+fn default_session_extractors() -> Vec<SessionExtractor> {
+    vec![
+        SessionExtractor::Header { name: "X-Session-Id".to_string() },
+        SessionExtractor::Query { name: "session".to_string() },
+    ]
+}
+
+fn default_sweep_interval_secs() -> u64 {
+    60
+}
+
+fn default_root_store() -> TlsRootStore {
+    TlsRootStore::System
 }
 
 fn default_proxy_mode() -> SessionMode {
@@ -56,9 +206,20 @@ impl Default for AppConfig {
             storage: StorageConfig {
                 type_: "memory".to_string(),
                 path: "./recordings".to_string(),
+                header_deny_list: Vec::new(),
+                spill_threshold_bytes: default_spill_threshold_bytes(),
+                body_files_path: default_body_files_path(),
             },
             auto_generate_sessions: false,
             proxy: ProxyConfig::default(),
+            matching: MatchConfig::default(),
+            replay: ReplayConfig::default(),
+            dynamic_values: Vec::new(),
+            captures: Vec::new(),
+            journal_limit: default_journal_limit(),
+            max_body_bytes: default_max_body_bytes(),
+            expiry: SessionExpiryConfig::default(),
+            session_extractors: default_session_extractors(),
         }
     }
 }
@@ -67,8 +228,37 @@ impl Default for AppConfig {
 impl Default for ProxyConfig {
     fn default() -> Self {
         Self {
+            default_mode: default_proxy_mode(),
             default_target: String::new(),
-            forward_host_header: true,
+            forward_host_header: false,
+            tls: TlsConfig::default(),
+            pool_max_idle_per_host: default_pool_max_idle_per_host(),
+            pool_idle_timeout_secs: default_pool_idle_timeout_secs(),
+            http2: false,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            read_timeout_ms: default_read_timeout_ms(),
+        }
+    }
+}
+
+// Default implementation for SessionExpiryConfig
+impl Default for SessionExpiryConfig {
+    fn default() -> Self {
+        Self {
+            idle_ttl_secs: 0,
+            sweep_interval_secs: default_sweep_interval_secs(),
+            cleanup_storage: false,
+        }
+    }
+}
+
+// Default implementation for TlsConfig
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            root_store: default_root_store(),
+            ca_bundle_path: None,
+            insecure_skip_verify: false,
         }
     }
 }
@@ -89,6 +279,9 @@ impl Default for StorageConfig {
         Self {
             type_: "memory".to_string(),
             path: "./recordings".to_string(),
+            header_deny_list: Vec::new(),
+            spill_threshold_bytes: default_spill_threshold_bytes(),
+            body_files_path: default_body_files_path(),
         }
     }
 }
\ No newline at end of file
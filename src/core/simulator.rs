@@ -22,7 +22,8 @@ impl ApiSimulator {
         let storage = StorageFactory::create_storage(&config.storage)?;
 
         // Initialize session manager with worker threads
-        let session_manager = Arc::new(SessionManager::new(storage.clone(), Some(config.clone())));
+        let session_manager = Arc::new(SessionManager::new(storage.clone(), Some(config.clone()))?);
+        session_manager.start_expiry_task();
 
         // Initialize HTTP server
         let server = Server::new(
@@ -49,4 +50,14 @@ impl ApiSimulator {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Run the simulator on a listener that is already bound, such as one on
+    // a free port picked by the OS
+    pub async fn serve(self, listener: tokio::net::TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Starting API Simulator on {}", listener.local_addr()?);
+
+        self.server.run_on(listener).await?;
+
+        Ok(())
+    }
+}
@@ -1,27 +1,63 @@
-use crate::session::{SessionManager, SessionId};
+use crate::session::{SessionManager, SessionMode, RequestOutcome, validate_session_id};
+use super::session_id::extract_session_id;
+use crate::storage::{StoredInteraction, StoredRequest, StoredResponse, StoredStub, StubRequest, StubResponse};
 use axum::{
-    body::{Bytes, Body, to_bytes},
-    extract::{Path, State, Query, Request},
-    http::{StatusCode, HeaderMap, Uri, Method},
-    response::{IntoResponse, Response},
+    extract::{Path, State, Request},
+    http::StatusCode,
+    response::IntoResponse,
     Json,
 };
 use log::{info, error};
-use serde::{Serialize, Deserialize};
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::sync::Arc;
-use uuid::Uuid;
 
-// Query parameters for session extraction
+// Session create payload
 #[derive(Debug, Deserialize)]
-pub struct SessionQuery {
-    pub session: Option<String>,
+pub struct CreateSessionPayload {
+    pub session_id: String,
+    #[serde(default)]
+    pub mode: Option<SessionMode>,
+    #[serde(default)]
+    pub target: Option<String>,
 }
 
-// Session create payload
+// Session clone payload
 #[derive(Debug, Deserialize)]
-pub struct CreateSessionPayload {
+pub struct CloneSessionPayload {
     pub session_id: String,
+    #[serde(default)]
+    pub mode: Option<SessionMode>,
+}
+
+// Stub create payload
+#[derive(Debug, Deserialize)]
+pub struct CreateStubPayload {
+    #[serde(default = "default_stub_priority")]
+    pub priority: i32,
+    pub request: StubRequest,
+    pub response: StubResponse,
+}
+
+// Stubs are preferred over recordings unless told otherwise
+fn default_stub_priority() -> i32 {
+    1
+}
+
+// Interaction update payload, the ID and timestamp are kept
+#[derive(Debug, Deserialize)]
+pub struct UpdateInteractionPayload {
+    pub request: StoredRequest,
+    pub response: StoredResponse,
+}
+
+// Request count payload, a matcher plus an optional outcome
+#[derive(Debug, Deserialize)]
+pub struct CountRequestsPayload {
+    #[serde(flatten)]
+    pub request: StubRequest,
+    #[serde(default)]
+    pub outcome: Option<RequestOutcome>,
 }
 
 // App state to share session manager
@@ -58,73 +94,316 @@ pub async fn create_session(
     if payload.session_id.is_empty() {
         return (StatusCode::BAD_REQUEST, "Missing session_id field").into_response();
     }
+    if let Err(err) = validate_session_id(&payload.session_id) {
+        return (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response();
+    }
+    match state.session_manager.session_id_taken(&payload.session_id).await {
+        Ok(false) => {},
+        Ok(true) => return (
+            StatusCode::CONFLICT,
+            format!("Error: Session {} already exists", payload.session_id),
+        ).into_response(),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
+    }
 
-    match state.session_manager.create_session(payload.session_id).await {
+    // Start from the defaults and apply the requested overrides
+    let mut config = state.session_manager.default_session_config();
+    if let Some(mode) = payload.mode {
+        config.mode = mode;
+    }
+    if let Some(target) = payload.target {
+        config.target = Some(target);
+    }
+
+    match state.session_manager.create_session_with_config(payload.session_id, config).await {
         Ok(_) => (StatusCode::CREATED, "Session created").into_response(),
         Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
     }
 }
 
-// Delete a session handler
-pub async fn delete_session(
+// Get a session's configuration handler
+pub async fn get_session(
     State(state): State<AppState>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
-    match state.session_manager.delete_session(&id).await {
+    match state.session_manager.get_session_config(&id).await {
+        Ok(config) => Json(config).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Clone a session handler
+pub async fn clone_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<CloneSessionPayload>,
+) -> impl IntoResponse {
+    if payload.session_id.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Missing session_id field").into_response();
+    }
+    if let Err(err) = validate_session_id(&payload.session_id) {
+        return (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response();
+    }
+    if !state.session_manager.session_exists(&id).await {
+        return (StatusCode::NOT_FOUND, format!("Error: Session {} not found", id)).into_response();
+    }
+    match state.session_manager.session_id_taken(&payload.session_id).await {
+        Ok(false) => {},
+        Ok(true) => return (
+            StatusCode::CONFLICT,
+            format!("Error: Session {} already exists", payload.session_id),
+        ).into_response(),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
+    }
+
+    match state.session_manager.clone_session(&id, payload.session_id, payload.mode).await {
+        Ok(config) => (StatusCode::CREATED, Json(config)).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Update a session's configuration handler, the body is a JSON merge patch
+pub async fn update_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(patch): Json<Value>,
+) -> impl IntoResponse {
+    if !state.session_manager.session_exists(&id).await {
+        return (StatusCode::NOT_FOUND, format!("Error: Session {} not found", id)).into_response();
+    }
+
+    let result = state.session_manager
+        .update_session_config(&id, |config| config.apply_patch(&patch))
+        .await;
+
+    match result {
+        Ok(_) => match state.session_manager.get_session_config(&id).await {
+            Ok(config) => Json(config).into_response(),
+            Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+        },
+        Err(err) => (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// List the misses journaled by a strict session handler
+pub async fn list_misses(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.get_misses(&id).await {
+        Ok(misses) => Json(misses).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Clear the misses journaled by a session handler
+pub async fn clear_misses(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.clear_misses(&id).await {
         Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// List the requests a session received handler
+pub async fn list_requests(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.get_journal(&id).await {
+        Ok(journal) => Json(journal).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Count the requests a session received handler
+pub async fn count_requests(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<CountRequestsPayload>,
+) -> impl IntoResponse {
+    if !state.session_manager.session_exists(&id).await {
+        return (StatusCode::NOT_FOUND, format!("Error: Session {} not found", id)).into_response();
+    }
+
+    match state.session_manager.count_requests(&id, &payload.request, payload.outcome).await {
+        Ok(count) => Json(json!({ "count": count })).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Clear the requests journaled by a session handler
+pub async fn clear_requests(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.clear_journal(&id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// List the interactions of a session handler
+pub async fn list_interactions(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.list_interactions(&id).await {
+        Ok(interactions) => Json(interactions).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Get an interaction handler
+pub async fn get_interaction(
+    State(state): State<AppState>,
+    Path((id, interaction_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match state.session_manager.get_interaction(&id, &interaction_id).await {
+        Ok(Some(interaction)) => Json(interaction).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            format!("Error: Interaction {} not found", interaction_id),
+        ).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Update an interaction handler
+pub async fn update_interaction(
+    State(state): State<AppState>,
+    Path((id, interaction_id)): Path<(String, String)>,
+    Json(payload): Json<UpdateInteractionPayload>,
+) -> impl IntoResponse {
+    let existing = match state.session_manager.get_interaction(&id, &interaction_id).await {
+        Ok(Some(interaction)) => interaction,
+        Ok(None) => return (
+            StatusCode::NOT_FOUND,
+            format!("Error: Interaction {} not found", interaction_id),
+        ).into_response(),
+        Err(err) => return (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    };
+
+    let interaction = StoredInteraction {
+        request: payload.request,
+        response: payload.response,
+        ..existing
+    };
+
+    match state.session_manager.update_interaction(&id, interaction).await {
+        Ok(interaction) => Json(interaction).into_response(),
         Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
     }
 }
 
-// Extract session ID from request
-fn extract_session_id(headers: &HeaderMap, query: &SessionQuery) -> SessionId {
-    // Try to get from header
-    if let Some(header) = headers.get("X-Session-Id") {
-        if let Ok(session_id) = header.to_str() {
-            return session_id.to_string();
-        }
+// Delete an interaction handler
+pub async fn delete_interaction(
+    State(state): State<AppState>,
+    Path((id, interaction_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if !state.session_manager.session_exists(&id).await {
+        return (StatusCode::NOT_FOUND, format!("Error: Session {} not found", id)).into_response();
     }
 
-    // Try to get from query parameter
-    if let Some(session) = &query.session {
-        return session.clone();
+    match state.session_manager.delete_interaction(&id, &interaction_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
     }
+}
 
-    // Fall back to default session
-    "default".to_string()
+// Create a stub handler
+pub async fn create_stub(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<CreateStubPayload>,
+) -> impl IntoResponse {
+    if !state.session_manager.session_exists(&id).await {
+        return (StatusCode::NOT_FOUND, format!("Error: Session {} not found", id)).into_response();
+    }
+
+    let stub = StoredStub::new(payload.priority, payload.request, payload.response);
+
+    match state.session_manager.create_stub(&id, stub).await {
+        Ok(stub) => (StatusCode::CREATED, Json(stub)).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response(),
+    }
 }
 
-// Main API request handler
-pub async fn handle_api_request(
+// List the stubs of a session handler
+pub async fn list_stubs(
     State(state): State<AppState>,
-    req: Request,
+    Path(id): Path<String>,
 ) -> impl IntoResponse {
-    // Extract headers and query params from the request
-    let headers = req.headers().clone();
-    let uri = req.uri().clone();
+    match state.session_manager.list_stubs(&id).await {
+        Ok(stubs) => Json(stubs).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
+
+// Delete a stub handler
+pub async fn delete_stub(
+    State(state): State<AppState>,
+    Path((id, stub_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match state.session_manager.delete_stub(&id, &stub_id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("Error: {}", err)).into_response(),
+    }
+}
 
-    // Parse query parameters
-    let query_params = req.uri().query()
-        .map(|q| {
-            serde_qs::from_str::<SessionQuery>(q)
-                .unwrap_or_else(|_| SessionQuery { session: None })
-        })
-        .unwrap_or_else(|| SessionQuery { session: None });
+// Delete a session handler
+pub async fn delete_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.session_manager.delete_session(&id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", err)).into_response(),
+    }
+}
 
-    // Extract session ID (always returns a valid session ID now)
-    let session_id = extract_session_id(&headers, &query_params);
+// Main API request handler
+pub async fn handle_api_request(
+    State(state): State<AppState>,
+    mut req: Request,
+) -> impl IntoResponse {
+    // Extract session ID, falling back to the default session
+    let session_id = extract_session_id(state.session_manager.session_extractors(), &mut req);
 
-    // Ensure session exists
+    // Ensure session exists. Sessions evicted while idle come back as they were
+    // persisted, unknown sessions are only created when configured to
     if !state.session_manager.session_exists(&session_id).await {
-        match state.session_manager.create_session(session_id.clone()).await {
-            Ok(_) => info!("Auto-created session: {}", session_id),
+        if let Err(err) = validate_session_id(&session_id) {
+            return (StatusCode::BAD_REQUEST, format!("Error: {}", err)).into_response();
+        }
+
+        match state.session_manager.restore_session(&session_id).await {
+            Ok(true) => {},
+            Ok(false) if !state.session_manager.auto_generate_sessions() => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!("Error: Session {} not found and auto_generate_sessions is disabled", session_id),
+                ).into_response();
+            },
+            Ok(false) => match state.session_manager.create_session(session_id.clone()).await {
+                Ok(_) => info!("Auto-created session: {}", session_id),
+                Err(err) => {
+                    error!("Failed to auto-create session: {}", err);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to create session: {}", err),
+                    ).into_response();
+                }
+            },
             Err(err) => {
-                error!("Failed to auto-create session: {}", err);
+                error!("Failed to restore session: {}", err);
                 return (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to create session: {}", err),
+                    format!("Failed to restore session: {}", err),
                 ).into_response();
-            }
+            },
         }
     }
 
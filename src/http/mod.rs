@@ -1,4 +1,5 @@
 mod server;
 mod handlers;
+mod session_id;
 
 pub use server::Server;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use crate::session::SessionManager;
 use axum::{
     Router,
-    routing::{get, post, delete, any},
+    routing::{get, post, delete},
 };
 use log::info;
 use std::net::SocketAddr;
@@ -13,7 +13,22 @@ use super::handlers::{
     get_server_info,
     list_sessions,
     create_session,
+    get_session,
+    update_session,
+    clone_session,
     delete_session,
+    list_misses,
+    clear_misses,
+    list_requests,
+    count_requests,
+    clear_requests,
+    list_interactions,
+    get_interaction,
+    update_interaction,
+    delete_interaction,
+    create_stub,
+    list_stubs,
+    delete_stub,
     handle_api_request,
 };
 
@@ -36,6 +51,18 @@ impl Server {
 
     // Run the server
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        // Parse the socket address
+        let addr: SocketAddr = format!("{}:{}", self.host, self.port).parse()?;
+
+        // Start the server
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Server started on http://{}", addr);
+
+        self.run_on(listener).await
+    }
+
+    // Run the server on a listener that is already bound
+    pub async fn run_on(self, listener: tokio::net::TcpListener) -> Result<(), Box<dyn std::error::Error>> {
         // Setup app state with session manager
         let state = crate::http::handlers::AppState {
             session_manager: self.session_manager.clone(),
@@ -46,7 +73,21 @@ impl Server {
             // Control API routes
             .route("/__api_simulator/info", get(get_server_info))
             .route("/__api_simulator/sessions", get(list_sessions).post(create_session))
-            .route("/__api_simulator/sessions/:id", delete(delete_session))
+            .route(
+                "/__api_simulator/sessions/:id",
+                get(get_session).patch(update_session).delete(delete_session),
+            )
+            .route("/__api_simulator/sessions/:id/clone", post(clone_session))
+            .route("/__api_simulator/sessions/:id/misses", get(list_misses).delete(clear_misses))
+            .route("/__api_simulator/sessions/:id/requests", get(list_requests).delete(clear_requests))
+            .route("/__api_simulator/sessions/:id/requests/count", post(count_requests))
+            .route("/__api_simulator/sessions/:id/interactions", get(list_interactions))
+            .route(
+                "/__api_simulator/sessions/:id/interactions/:interaction_id",
+                get(get_interaction).put(update_interaction).delete(delete_interaction),
+            )
+            .route("/__api_simulator/sessions/:id/stubs", get(list_stubs).post(create_stub))
+            .route("/__api_simulator/sessions/:id/stubs/:stub_id", delete(delete_stub))
             // Main API simulator route - handle all other requests
             .fallback(handle_api_request)
             .with_state(state)
@@ -56,13 +97,8 @@ impl Server {
                     .layer(TraceLayer::new_for_http())
             );
 
-        // Parse the socket address
-        let addr: SocketAddr = format!("{}:{}", self.host, self.port).parse()?;
-
-        // Start the server
-        info!("Server started on http://{}", addr);
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        axum::serve(listener, app).await?;
+        // Connection info lets sessions be told apart by client address
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
         Ok(())
     }
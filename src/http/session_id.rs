@@ -0,0 +1,146 @@
+use crate::config::SessionExtractor;
+use crate::session::{SessionId, DEFAULT_SESSION};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header::COOKIE, HeaderValue, Uri},
+};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde_json::Value;
+use std::net::SocketAddr;
+
+// Find the session a request belongs to, trying each extractor in order.
+// Whatever only served to name the session is removed from the request,
+// so it never reaches the upstream
+pub fn extract_session_id(extractors: &[SessionExtractor], req: &mut Request) -> SessionId {
+    for extractor in extractors {
+        let session_id = match extractor {
+            SessionExtractor::Header { name } => from_header(req, name),
+            SessionExtractor::Query { name } => from_query(req, name),
+            SessionExtractor::Cookie { name } => from_cookie(req, name),
+            SessionExtractor::PathPrefix { prefix } => from_path_prefix(req, prefix),
+            SessionExtractor::JwtClaim { claim, header } => from_jwt_claim(req, header, claim),
+            SessionExtractor::ClientIp => req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        };
+
+        if let Some(session_id) = session_id.filter(|id| !id.is_empty()) {
+            return session_id;
+        }
+    }
+
+    // Fall back to default session
+    DEFAULT_SESSION.to_string()
+}
+
+// Take the session from a header
+fn from_header(req: &mut Request, name: &str) -> Option<String> {
+    let session_id = req.headers().get(name)?.to_str().ok()?.to_string();
+    req.headers_mut().remove(name);
+    Some(session_id)
+}
+
+// Take the session from a query parameter. The other parameters are kept
+// byte for byte, so the upstream and the recording see them as sent
+fn from_query(req: &mut Request, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    let mut session_id = None;
+    let mut kept = Vec::new();
+
+    for segment in query.split('&') {
+        match form_urlencoded::parse(segment.as_bytes()).next() {
+            Some((key, value)) if key == name => {
+                session_id.get_or_insert(value.into_owned());
+            },
+            _ => kept.push(segment),
+        }
+    }
+
+    let session_id = session_id?;
+
+    let query = kept.join("&");
+    let path_and_query = if query.is_empty() {
+        req.uri().path().to_string()
+    } else {
+        format!("{}?{}", req.uri().path(), query)
+    };
+    set_path_and_query(req, &path_and_query)?;
+
+    Some(session_id)
+}
+
+// Take the session from a cookie
+fn from_cookie(req: &mut Request, name: &str) -> Option<String> {
+    let mut session_id = None;
+    let mut kept = Vec::new();
+
+    for header in req.headers().get_all(COOKIE) {
+        for pair in header.to_str().ok()?.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=') {
+                Some((key, value)) if key == name && session_id.is_none() => {
+                    session_id = Some(value.to_string());
+                },
+                _ => kept.push(pair.to_string()),
+            }
+        }
+    }
+
+    let session_id = session_id?;
+
+    req.headers_mut().remove(COOKIE);
+    if !kept.is_empty() {
+        let cookies = HeaderValue::from_str(&kept.join("; ")).ok()?;
+        req.headers_mut().insert(COOKIE, cookies);
+    }
+
+    Some(session_id)
+}
+
+// Take the session from the path segment after a prefix
+fn from_path_prefix(req: &mut Request, prefix: &str) -> Option<String> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = req.uri().path().strip_prefix(prefix)?.strip_prefix('/')?;
+
+    let (session_id, path) = match rest.split_once('/') {
+        Some((session_id, path)) => (session_id.to_string(), format!("/{}", path)),
+        None => (rest.to_string(), "/".to_string()),
+    };
+
+    // Without a session the path is left as it is for the next extractor
+    if session_id.is_empty() {
+        return None;
+    }
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    set_path_and_query(req, &path_and_query)?;
+
+    Some(session_id)
+}
+
+// Read the session from a claim of the JWT in a header
+fn from_jwt_claim(req: &Request, header: &str, claim: &str) -> Option<String> {
+    let value = req.headers().get(header)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ").unwrap_or(value).trim();
+
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: Value = serde_json::from_slice(&payload).ok()?;
+
+    match claims.get(claim)? {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+// Replace the path and query of a request, keeping its scheme and authority
+fn set_path_and_query(req: &mut Request, path_and_query: &str) -> Option<()> {
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    *req.uri_mut() = Uri::from_parts(parts).ok()?;
+    Some(())
+}
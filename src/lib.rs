@@ -4,6 +4,8 @@ pub mod http;
 pub mod matching;
 pub mod session;
 pub mod storage;
+pub mod template;
+pub mod upstream;
 
 pub use config::AppConfig;
 pub use core::ApiSimulator;
\ No newline at end of file
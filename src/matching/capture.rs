@@ -0,0 +1,43 @@
+use crate::matching::CaptureRule;
+use crate::matching::matcher::parse_json_path;
+use serde_json::Value;
+
+impl CaptureRule {
+    // Check that exactly one way of locating the value is set, the regex is
+    // already compiled when the rule is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.json_path, &self.regex) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err(format!("Capture {} needs either json_path or regex", self.name)),
+        }
+    }
+
+    // Extract the captured value from a body
+    pub fn extract(&self, body: &[u8]) -> Option<String> {
+        if let Some(path) = &self.json_path {
+            let json = serde_json::from_slice::<Value>(body).ok()?;
+            return lookup_json_path(&json, &parse_json_path(path)).and_then(|value| match value {
+                Value::String(text) => Some(text.clone()),
+                Value::Number(number) => Some(number.to_string()),
+                Value::Bool(flag) => Some(flag.to_string()),
+                _ => None,
+            });
+        }
+
+        let text = std::str::from_utf8(body).ok()?;
+        let captures = self.regex.as_ref()?.regex().captures(text)?;
+
+        captures.get(1)
+            .or_else(|| captures.get(0))
+            .map(|matched| matched.as_str().to_string())
+    }
+}
+
+// Find the value at a JSON path
+fn lookup_json_path<'a>(value: &'a Value, segments: &[String]) -> Option<&'a Value> {
+    segments.iter().try_fold(value, |current, segment| match current {
+        Value::Object(obj) => obj.get(segment),
+        Value::Array(arr) => segment.parse::<usize>().ok().and_then(|index| arr.get(index)),
+        _ => None,
+    })
+}
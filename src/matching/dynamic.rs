@@ -1,12 +1,23 @@
+use crate::matching::DynamicValueRule;
 use regex::Regex;
 use std::collections::HashMap;
 
+// Placeholder volatile values are replaced with before matching
+const NORMALIZED_VALUE: &str = "{{dynamic}}";
+
 // Dynamic value handling
 pub struct DynamicValueProcessor {
     patterns: Vec<(Regex, String)>,
     values: HashMap<String, String>,
 }
 
+// Default implementation for DynamicValueProcessor
+impl Default for DynamicValueProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DynamicValueProcessor {
     // Create a new dynamic value processor
     pub fn new() -> Self {
@@ -16,6 +27,39 @@ impl DynamicValueProcessor {
         }
     }
 
+    // Create a processor from configured rules, reusing values generated earlier.
+    // The rules' patterns were compiled when the configuration was loaded
+    pub fn from_rules(rules: &[DynamicValueRule], values: HashMap<String, String>) -> Self {
+        Self {
+            patterns: rules.iter()
+                .map(|rule| (rule.pattern.regex().clone(), rule.generator.clone()))
+                .collect(),
+            values,
+        }
+    }
+
+    // Check if any pattern is configured
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // Take the generated values so they can be reused by a later processor
+    pub fn into_values(self) -> HashMap<String, String> {
+        self.values
+    }
+
+    // Replace every dynamic value with the same placeholder, so bodies that
+    // only differ in volatile values compare equal
+    pub fn normalize(&self, body: &str) -> String {
+        let mut result = body.to_string();
+
+        for (regex, _) in &self.patterns {
+            result = regex.replace_all(&result, NORMALIZED_VALUE).into_owned();
+        }
+
+        result
+    }
+
     // Add a pattern
     pub fn add_pattern(&mut self, pattern: &str, generator: &str) -> Result<(), String> {
         let regex = Regex::new(pattern)
@@ -31,7 +75,7 @@ impl DynamicValueProcessor {
 
         // Extract dynamic values
         for (regex, generator) in &self.patterns {
-            let captures = regex.captures_iter(&body);
+            let captures = regex.captures_iter(body);
 
             for capture in captures {
                 if let Some(matched) = capture.get(0) {
@@ -0,0 +1,25 @@
+use axum::http::{HeaderMap, header::CONTENT_TYPE};
+
+// Check if a request is a gRPC call, whatever its message encoding
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+// Split a gRPC body into its length-prefixed messages, None when the body is
+// not a sequence of whole messages
+pub(crate) fn grpc_messages(body: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut messages = Vec::new();
+    let mut rest = body;
+
+    while !rest.is_empty() {
+        // A compressed flag, then the length as a big-endian u32
+        let prefix = rest.get(..5)?;
+        let length = u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+        messages.push(rest.get(5..5 + length)?);
+        rest = &rest[5 + length..];
+    }
+
+    Some(messages)
+}
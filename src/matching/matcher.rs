@@ -1,22 +1,34 @@
-use crate::storage::Storage;
+use crate::matching::{DynamicValueProcessor, MatchConfig, QueryMatching, MatchFailure, MissCandidate};
+use crate::matching::grpc::{grpc_messages, is_grpc};
+use crate::storage::{Storage, StoredInteraction, stored_to_request};
 use axum::{
     body::Bytes,
     extract::Request,
-    response::Response,
+    http::HeaderMap,
 };
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use log::{debug, info};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 // Result of a match operation
 pub enum MatchResult {
-    Match(Response<Bytes>),
+    // Equally good matches in recording order, never empty
+    Match(Vec<StoredInteraction>),
     NoMatch,
 }
 
-// Request matcher that handles finding and processing stored interactions
-pub struct RequestMatcher {
-    // Will hold patterns and matching configuration
+// Request matcher that handles finding and processing stored interactions,
+// the criteria to apply come from each session's MatchConfig
+pub struct RequestMatcher {}
+
+// Default implementation for RequestMatcher
+impl Default for RequestMatcher {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RequestMatcher {
@@ -31,42 +43,317 @@ impl RequestMatcher {
         req: &Request<Bytes>,
         session_id: &str,
         storage: &Arc<dyn Storage>,
+        config: &MatchConfig,
+        dynamic: &DynamicValueProcessor,
     ) -> Result<MatchResult, String> {
-        // Get method and path
-        let method = req.method().clone();
-        let path = req.uri().path().to_string();
-        let query = req.uri().query().map(|q| q.to_string());
+        // Volatile values must not prevent a match
+        let req = &normalize_request(req, dynamic);
 
-        // For body matching, we'd need a way to check the request body
-        // This is a simplified approach for this example
-
-        // Get all interactions for this session
-        let interactions = storage.get_interactions(session_id)
+        // Get all interactions for this session, in recording order
+        let interactions = storage.list_interactions(session_id)
             .map_err(|e| format!("Failed to get interactions: {}", e))?;
 
         debug!("Matching request against {} interactions", interactions.len());
 
-        for (stored_req, response) in interactions {
-            // Check if method matches
-            if stored_req.method() != req.method() {
+        // Highest score wins, tied interactions are replayed in recording order
+        let mut best_score = 0;
+        let mut best = Vec::new();
+
+        for interaction in interactions {
+            let stored_req = normalize_request(&stored_to_request(&interaction.request)?, dynamic);
+
+            if !self.is_candidate(req, &stored_req, config) {
                 continue;
             }
 
-            // Check if path matches
-            if stored_req.uri().path() != req.uri().path() {
-                continue;
+            let score = self.score(req, &stored_req, config);
+            debug!("Interaction {} scored {}", interaction.id, score);
+
+            if best.is_empty() || score > best_score {
+                best_score = score;
+                best = vec![interaction];
+            } else if score == best_score {
+                best.push(interaction);
             }
+        }
+
+        if best.is_empty() {
+            // No match found
+            debug!("No matching interaction found");
+            return Ok(MatchResult::NoMatch);
+        }
+
+        info!("Found {} matching interaction(s) with score {}", best.len(), best_score);
+        Ok(MatchResult::Match(best))
+    }
+
+    // Find the stored interactions closest to a request that matched nothing
+    pub async fn explain_miss(
+        &self,
+        req: &Request<Bytes>,
+        session_id: &str,
+        storage: &Arc<dyn Storage>,
+        config: &MatchConfig,
+        dynamic: &DynamicValueProcessor,
+        limit: usize,
+    ) -> Result<Vec<MissCandidate>, String> {
+        let req = &normalize_request(req, dynamic);
+
+        let interactions = storage.list_interactions(session_id)
+            .map_err(|e| format!("Failed to get interactions: {}", e))?;
 
-            // In this simplified version, we match only on method and path
-            // A more sophisticated matcher would compare bodies and other elements
+        let mut candidates = Vec::new();
 
-            info!("Found matching interaction");
-            return Ok(MatchResult::Match(response));
+        for interaction in interactions {
+            let stored_req = normalize_request(&stored_to_request(&interaction.request)?, dynamic);
+            let failures = self.failures(req, &stored_req, config);
+            let score = self.score(req, &stored_req, config);
+
+            candidates.push((failures.len(), score, MissCandidate {
+                interaction_id: interaction.id,
+                method: interaction.request.method,
+                uri: interaction.request.uri,
+                failures,
+            }));
         }
 
-        // No match found
-        debug!("No matching interaction found");
-        Ok(MatchResult::NoMatch)
+        // Fewest failed criteria first, then the most similar, then recording order
+        candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+        Ok(candidates.into_iter()
+            .take(limit)
+            .map(|(_, _, candidate)| candidate)
+            .collect())
+    }
+
+    // List every criterion a stored request fails to meet
+    fn failures(&self, req: &Request<Bytes>, stored_req: &Request<Bytes>, config: &MatchConfig) -> Vec<MatchFailure> {
+        let mut failures = Vec::new();
+
+        let mut fail = |criterion: &str, field: Option<String>, expected: Option<String>, actual: Option<String>| {
+            failures.push(MatchFailure {
+                criterion: criterion.to_string(),
+                field,
+                expected,
+                actual,
+            });
+        };
+
+        if stored_req.method() != req.method() {
+            fail("method", None, Some(stored_req.method().to_string()), Some(req.method().to_string()));
+        }
+
+        if stored_req.uri().path() != req.uri().path() {
+            fail("path", None, Some(stored_req.uri().path().to_string()), Some(req.uri().path().to_string()));
+        }
+
+        if !self.query_matches(req.uri().query(), stored_req.uri().query(), config.query) {
+            fail(
+                "query",
+                None,
+                stored_req.uri().query().map(|q| q.to_string()),
+                req.uri().query().map(|q| q.to_string()),
+            );
+        }
+
+        for name in &config.headers {
+            if !self.headers_match(req.headers(), stored_req.headers(), std::slice::from_ref(name)) {
+                let value = |headers: &HeaderMap| headers.get(name.as_str())
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string());
+                fail("header", Some(name.clone()), value(stored_req.headers()), value(req.headers()));
+            }
+        }
+
+        if compares_body(req, config) && !self.body_matches(req.body(), stored_req.body(), &config.ignored_body_paths) {
+            let parsed = (
+                serde_json::from_slice::<Value>(req.body()),
+                serde_json::from_slice::<Value>(stored_req.body()),
+            );
+
+            let mut field_failures = 0;
+
+            // gRPC calls name the messages that differ
+            let messages = (is_grpc(req.headers()), grpc_messages(req.body()), grpc_messages(stored_req.body()));
+            if let (true, Some(actual), Some(expected)) = messages {
+                for index in 0..actual.len().max(expected.len()) {
+                    let (actual, expected) = (actual.get(index), expected.get(index));
+                    if actual == expected {
+                        continue;
+                    }
+
+                    field_failures += 1;
+                    fail(
+                        "body",
+                        Some(format!("message[{}]", index)),
+                        expected.map(|message| STANDARD.encode(message)),
+                        actual.map(|message| STANDARD.encode(message)),
+                    );
+                }
+            }
+
+            if let (Ok(mut actual_json), Ok(mut expected_json)) = parsed {
+                for path in &config.ignored_body_paths {
+                    let segments = parse_json_path(path);
+                    remove_json_path(&mut actual_json, &segments);
+                    remove_json_path(&mut expected_json, &segments);
+                }
+
+                let mut actual_fields = Vec::new();
+                flatten_json(&actual_json, String::new(), &mut actual_fields);
+                let actual_fields: HashMap<String, Value> = actual_fields.into_iter().collect();
+
+                let mut expected_fields = Vec::new();
+                flatten_json(&expected_json, String::new(), &mut expected_fields);
+
+                for (path, expected) in expected_fields {
+                    let actual = actual_fields.get(&path);
+                    if actual.is_some_and(|actual| self.json_matches(actual, &expected)) {
+                        continue;
+                    }
+
+                    field_failures += 1;
+                    fail(
+                        "body",
+                        Some(format!("${}", path)),
+                        Some(expected.to_string()),
+                        actual.map(|value| value.to_string()),
+                    );
+                }
+            }
+
+            // Bodies that differ in shape or are not JSON get a single failure
+            if field_failures == 0 {
+                fail(
+                    "body",
+                    None,
+                    Some(String::from_utf8_lossy(stored_req.body()).to_string()),
+                    Some(String::from_utf8_lossy(req.body()).to_string()),
+                );
+            }
+        }
+
+        failures
+    }
+
+    // Check the criteria a stored request must meet to be considered at all
+    fn is_candidate(&self, req: &Request<Bytes>, stored_req: &Request<Bytes>, config: &MatchConfig) -> bool {
+        // Method and path always have to match
+        if stored_req.method() != req.method() || stored_req.uri().path() != req.uri().path() {
+            return false;
+        }
+
+        // Then the optional criteria enabled for this session
+        self.query_matches(req.uri().query(), stored_req.uri().query(), config.query)
+            && self.headers_match(req.headers(), stored_req.headers(), &config.headers)
+            && (!compares_body(req, config) || self.body_matches(req.body(), stored_req.body(), &config.ignored_body_paths))
+    }
+
+    // Score how closely a stored request resembles the incoming one
+    fn score(&self, req: &Request<Bytes>, stored_req: &Request<Bytes>, config: &MatchConfig) -> usize {
+        // Candidates always share the path
+        let mut score = 1;
+
+        // One point per shared query parameter, one more if the parameters are identical
+        let actual_query = parse_query(req.uri().query().unwrap_or(""));
+        let expected_query = parse_query(stored_req.uri().query().unwrap_or(""));
+        score += actual_query.iter().filter(|pair| expected_query.contains(pair)).count();
+        if actual_query == expected_query {
+            score += 1;
+        }
+
+        // One point per equal value of the headers the session matches on. Others
+        // such as User-Agent or Accept are shared by accident and must not
+        // outweigh the query or the body
+        score += config.headers.iter()
+            .map(|name| {
+                let stored = stored_req.headers().get_all(name.as_str());
+                req.headers().get_all(name.as_str()).iter()
+                    .filter(|value| stored.iter().any(|stored| stored == *value))
+                    .count()
+            })
+            .sum::<usize>();
+
+        // One point per equal gRPC message
+        if let (true, Some(actual), Some(expected)) = (is_grpc(req.headers()), grpc_messages(req.body()), grpc_messages(stored_req.body())) {
+            score += actual.iter().zip(&expected).filter(|(actual, expected)| actual == expected).count();
+        }
+
+        // One point per equal JSON field, one more if the bodies are identical
+        let parsed = (
+            serde_json::from_slice::<Value>(req.body()),
+            serde_json::from_slice::<Value>(stored_req.body()),
+        );
+
+        match parsed {
+            (Ok(mut actual_json), Ok(mut expected_json)) => {
+                for path in &config.ignored_body_paths {
+                    let segments = parse_json_path(path);
+                    remove_json_path(&mut actual_json, &segments);
+                    remove_json_path(&mut expected_json, &segments);
+                }
+
+                let mut expected_fields = Vec::new();
+                flatten_json(&expected_json, String::new(), &mut expected_fields);
+                let expected_fields: HashMap<String, Value> = expected_fields.into_iter().collect();
+
+                let mut actual_fields = Vec::new();
+                flatten_json(&actual_json, String::new(), &mut actual_fields);
+
+                score += actual_fields.iter()
+                    .filter(|(path, value)| expected_fields.get(path) == Some(value))
+                    .count();
+
+                if actual_json == expected_json {
+                    score += 1;
+                }
+            },
+            _ => {
+                if req.body() == stored_req.body() {
+                    score += 1;
+                }
+            },
+        }
+
+        score
+    }
+
+    // Check if two query strings match
+    fn query_matches(&self, actual: Option<&str>, expected: Option<&str>, mode: QueryMatching) -> bool {
+        match mode {
+            QueryMatching::Ignore => true,
+            QueryMatching::Exact => actual.unwrap_or("") == expected.unwrap_or(""),
+            QueryMatching::Unordered => {
+                parse_query(actual.unwrap_or("")) == parse_query(expected.unwrap_or(""))
+            },
+        }
+    }
+
+    // Check if the selected headers have the same values
+    fn headers_match(&self, actual: &HeaderMap, expected: &HeaderMap, names: &[String]) -> bool {
+        names.iter().all(|name| {
+            actual.get_all(name.as_str()).iter().eq(expected.get_all(name.as_str()).iter())
+        })
+    }
+
+    // Check if two bodies match, deep matching them when both are JSON
+    fn body_matches(&self, actual: &Bytes, expected: &Bytes, ignored_paths: &[String]) -> bool {
+        let parsed = (
+            serde_json::from_slice::<Value>(actual),
+            serde_json::from_slice::<Value>(expected),
+        );
+
+        match parsed {
+            (Ok(mut actual_json), Ok(mut expected_json)) => {
+                for path in ignored_paths {
+                    let segments = parse_json_path(path);
+                    remove_json_path(&mut actual_json, &segments);
+                    remove_json_path(&mut expected_json, &segments);
+                }
+
+                self.json_matches(&actual_json, &expected_json)
+            },
+            _ => actual == expected,
+        }
     }
 
     // Check if two JSON values match
@@ -106,4 +393,108 @@ impl RequestMatcher {
             _ => actual == expected,
         }
     }
-}
\ No newline at end of file
+}
+
+// Bodies are compared when the session asks for it, and always for gRPC calls
+// since the path only names the method
+fn compares_body(req: &Request<Bytes>, config: &MatchConfig) -> bool {
+    config.body || is_grpc(req.headers())
+}
+
+// Copy a request with the dynamic values in its body normalized
+fn normalize_request(req: &Request<Bytes>, dynamic: &DynamicValueProcessor) -> Request<Bytes> {
+    let body = match std::str::from_utf8(req.body()) {
+        Ok(text) if !dynamic.is_empty() => Bytes::from(dynamic.normalize(text)),
+        _ => req.body().clone(),
+    };
+
+    let mut normalized = Request::new(body);
+    *normalized.method_mut() = req.method().clone();
+    *normalized.uri_mut() = req.uri().clone();
+    *normalized.headers_mut() = req.headers().clone();
+    normalized
+}
+
+// Parse a query string into sorted, decoded key/value pairs
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+// Collect the leaf values of a JSON document keyed by their path
+fn flatten_json(value: &Value, path: String, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(obj) if !obj.is_empty() => {
+            for (key, child) in obj {
+                flatten_json(child, format!("{}.{}", path, key), out);
+            }
+        },
+        Value::Array(arr) if !arr.is_empty() => {
+            for (index, child) in arr.iter().enumerate() {
+                flatten_json(child, format!("{}[{}]", path, index), out);
+            }
+        },
+        _ => out.push((path, value.clone())),
+    }
+}
+
+// Split a JSON path such as $.items[0].id into its segments
+pub(crate) fn parse_json_path(path: &str) -> Vec<String> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+
+    path.replace('[', ".")
+        .replace(']', "")
+        .split('.')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_string())
+        .collect()
+}
+
+// Remove the value at a JSON path, * stands for every key or element
+fn remove_json_path(value: &mut Value, segments: &[String]) {
+    let (first, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    match value {
+        Value::Object(obj) => {
+            if rest.is_empty() {
+                if first == "*" {
+                    obj.clear();
+                } else {
+                    obj.remove(first);
+                }
+            } else if first == "*" {
+                for child in obj.values_mut() {
+                    remove_json_path(child, rest);
+                }
+            } else if let Some(child) = obj.get_mut(first) {
+                remove_json_path(child, rest);
+            }
+        },
+        Value::Array(arr) => {
+            // Array elements are nulled rather than removed so indexes stay stable
+            let targets: Vec<&mut Value> = if first == "*" {
+                arr.iter_mut().collect()
+            } else {
+                first.parse::<usize>().ok()
+                    .and_then(|index| arr.get_mut(index))
+                    .into_iter()
+                    .collect()
+            };
+
+            for child in targets {
+                if rest.is_empty() {
+                    *child = Value::Null;
+                } else {
+                    remove_json_path(child, rest);
+                }
+            }
+        },
+        _ => {},
+    }
+}
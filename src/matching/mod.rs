@@ -1,5 +1,14 @@
 mod matcher;
 mod dynamic;
+mod models;
+mod capture;
+mod stub;
+mod grpc;
+mod pattern;
 
 pub use matcher::{RequestMatcher, MatchResult};
 pub use dynamic::DynamicValueProcessor;
+pub use pattern::{Pattern, deserialize_whole};
+pub use models::{
+    MatchConfig, QueryMatching, MatchFailure, MissCandidate, DynamicValueRule, CaptureRule, CaptureSource,
+};
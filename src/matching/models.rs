@@ -0,0 +1,98 @@
+use crate::matching::Pattern;
+use serde::{Serialize, Deserialize};
+
+// How the query string takes part in matching
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum QueryMatching {
+    // The query string is not compared
+    Ignore,
+    // The query string must be byte-for-byte identical
+    Exact,
+    // The same parameters must be present, in any order
+    Unordered,
+}
+
+// Criteria a stored interaction must meet to match a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchConfig {
+    #[serde(default = "default_query_matching")]
+    pub query: QueryMatching,
+    // Headers whose values must be equal, compared case-insensitively by name
+    #[serde(default)]
+    pub headers: Vec<String>,
+    // Compare bodies, JSON bodies are deep matched and others compared as bytes
+    #[serde(default)]
+    pub body: bool,
+    // JSON paths left out of body matching, e.g. $.meta.requestId or items[*].id
+    #[serde(default)]
+    pub ignored_body_paths: Vec<String>,
+}
+
+fn default_query_matching() -> QueryMatching {
+    QueryMatching::Ignore
+}
+
+// Default implementation for MatchConfig
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            query: default_query_matching(),
+            headers: Vec::new(),
+            body: false,
+            ignored_body_paths: Vec::new(),
+        }
+    }
+}
+
+// Volatile value found by a regex and replaced by a generator, the generator is
+// consistent_random, increment, or a literal replacement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DynamicValueRule {
+    pub pattern: Pattern,
+    pub generator: String,
+}
+
+// Which body of an interaction a capture rule reads
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum CaptureSource {
+    Request,
+    Response,
+}
+
+// Value captured from recorded interactions and correlated with the value
+// seen live, set either json_path or regex (first group, or the whole match)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRule {
+    pub name: String,
+    #[serde(default = "default_capture_source")]
+    pub source: CaptureSource,
+    #[serde(default)]
+    pub json_path: Option<String>,
+    #[serde(default)]
+    pub regex: Option<Pattern>,
+}
+
+fn default_capture_source() -> CaptureSource {
+    CaptureSource::Response
+}
+
+// A criterion a stored interaction failed to meet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchFailure {
+    // One of method, path, query, header or body
+    pub criterion: String,
+    // Header name or JSON path the failure is about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+// A stored interaction that came close to matching a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissCandidate {
+    pub interaction_id: String,
+    pub method: String,
+    pub uri: String,
+    pub failures: Vec<MatchFailure>,
+}
@@ -0,0 +1,75 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+// Regex compiled once when it is loaded, kept next to the pattern it was
+// written as so it serializes back unchanged
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    // Compile a pattern matching anywhere in the text
+    pub fn new(source: &str) -> Result<Self, String> {
+        let regex = Regex::new(source).map_err(|e| format!("Invalid regex {}: {}", source, e))?;
+        Ok(Self { source: source.to_string(), regex })
+    }
+
+    // Compile a pattern the whole text has to match, not just part of it
+    pub fn whole(source: &str) -> Result<Self, String> {
+        let regex = Regex::new(&format!("^(?:{})$", source))
+            .map_err(|e| format!("Invalid regex {}: {}", source, e))?;
+        Ok(Self { source: source.to_string(), regex })
+    }
+
+    // The pattern as it was written
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.source, f)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        Pattern::new(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+// Deserialize an optional pattern the whole text has to match
+pub fn deserialize_whole<'de, D>(deserializer: D) -> Result<Option<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|source| Pattern::whole(&source))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
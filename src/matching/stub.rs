@@ -0,0 +1,91 @@
+use crate::matching::RequestMatcher;
+use crate::storage::{Storage, StoredStub, StubRequest, StubResponse};
+use axum::{body::Bytes, extract::Request};
+use log::debug;
+use serde_json::Value;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+impl StubResponse {
+    // Check the parts of a stub serde cannot
+    pub fn validate(&self) -> Result<(), String> {
+        // Body files are named relative to the body files directory
+        if let Some(path) = &self.body_file {
+            if !Path::new(path).components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(format!("body_file {} must be a relative path without ..", path));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StubRequest {
+    // Check whether a request meets every criterion of the stub
+    pub fn matches(&self, req: &Request<Bytes>) -> bool {
+        if let Some(method) = &self.method {
+            if !method.eq_ignore_ascii_case(req.method().as_str()) {
+                return false;
+            }
+        }
+
+        if self.path.as_ref().is_some_and(|path| path != req.uri().path()) {
+            return false;
+        }
+
+        if self.path_regex.as_ref().is_some_and(|pattern| !pattern.is_match(req.uri().path())) {
+            return false;
+        }
+
+        let query: Vec<(String, String)> = req.uri().query()
+            .map(|query| form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+        let query_matches = self.query.iter()
+            .all(|(name, value)| query.iter().any(|(n, v)| n == name && v == value));
+        if !query_matches {
+            return false;
+        }
+
+        let headers_match = self.headers.iter().all(|(name, value)| {
+            req.headers().get_all(name.as_str()).iter().any(|actual| actual.as_bytes() == value.as_bytes())
+        });
+        if !headers_match {
+            return false;
+        }
+
+        match &self.body {
+            Some(expected) => serde_json::from_slice::<Value>(req.body())
+                .is_ok_and(|actual| json_contains(&actual, expected)),
+            None => true,
+        }
+    }
+}
+
+impl RequestMatcher {
+    // Find the stub that answers a request, the highest priority wins
+    pub async fn match_stub(
+        &self,
+        req: &Request<Bytes>,
+        session_id: &str,
+        storage: &Arc<dyn Storage>,
+    ) -> Result<Option<StoredStub>, String> {
+        let stubs = storage.list_stubs(session_id)
+            .map_err(|e| format!("Failed to get stubs: {}", e))?;
+
+        let stub = stubs.into_iter().find(|stub| stub.request.matches(req));
+        if let Some(stub) = &stub {
+            debug!("Request matched stub {} with priority {}", stub.id, stub.priority);
+        }
+
+        Ok(stub)
+    }
+}
+
+// Check that every field of the expected JSON is present in the actual JSON,
+// arrays and scalars have to be equal
+fn json_contains(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Object(actual), Value::Object(expected)) => expected.iter()
+            .all(|(key, value)| actual.get(key).is_some_and(|actual| json_contains(actual, value))),
+        _ => actual == expected,
+    }
+}
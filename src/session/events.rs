@@ -0,0 +1,82 @@
+use crate::session::EventTiming;
+use crate::storage::StoredEvent;
+
+use axum::body::{Body, Bytes};
+use futures_util::StreamExt;
+
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+// Content type of a server-sent event stream
+pub(crate) const EVENT_STREAM: &str = "text/event-stream";
+
+// Splits a server-sent event stream into events as it is relayed, noting
+// when each one was complete
+pub(crate) struct EventRecorder {
+    start: Instant,
+    pending: Vec<u8>,
+    events: Vec<StoredEvent>,
+}
+
+impl EventRecorder {
+    // Offsets are measured from the moment the recorder is created
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            pending: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    // Add a chunk of the stream, events may span chunks
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+
+        while let Some(end) = event_end(&self.pending) {
+            let event: Vec<u8> = self.pending.drain(..end).collect();
+            self.record(&event);
+        }
+    }
+
+    // Events recorded so far, an unterminated last event included
+    pub(crate) fn finish(mut self) -> Vec<StoredEvent> {
+        if !self.pending.is_empty() {
+            let event = std::mem::take(&mut self.pending);
+            self.record(&event);
+        }
+        self.events
+    }
+
+    fn record(&mut self, event: &[u8]) {
+        self.events.push(StoredEvent {
+            offset_ms: self.start.elapsed().as_millis() as u64,
+            data: String::from_utf8_lossy(event).into_owned(),
+        });
+    }
+}
+
+// Position just past the blank line ending the first event, if any
+fn event_end(data: &[u8]) -> Option<usize> {
+    [&b"\r\n\r\n"[..], &b"\n\n"[..], &b"\r\r"[..]]
+        .iter()
+        .filter_map(|delimiter| {
+            data.windows(delimiter.len())
+                .position(|window| window == *delimiter)
+                .map(|start| start + delimiter.len())
+        })
+        .min()
+}
+
+// Stream recorded events, each one sent once its offset, divided by the
+// speed, has passed since the start of the response
+pub(crate) fn replay_events(events: Vec<StoredEvent>, timing: EventTiming, speed: f64) -> Body {
+    let start = tokio::time::Instant::now();
+
+    Body::from_stream(futures_util::stream::iter(events).then(move |event| async move {
+        if timing == EventTiming::Original {
+            let delay = Duration::from_secs_f64(event.offset_ms as f64 / 1000.0 / speed);
+            tokio::time::sleep_until(start + delay).await;
+        }
+        Ok::<_, Infallible>(Bytes::from(event.data))
+    }))
+}
@@ -0,0 +1,110 @@
+use crate::session::{Delay, LatencyConfig, PercentilePoint};
+use crate::storage::StoredLatency;
+
+use axum::{body::{Body, Bytes}, extract::Request};
+use futures_util::StreamExt;
+use http_body_util::{BodyStream, StreamBody};
+use rand::Rng;
+
+use std::time::Duration;
+
+// Delays of one replayed response
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ResponseDelay {
+    // Before the head is sent
+    pub(crate) head: Duration,
+    // Between the head and the end of the body
+    pub(crate) body: Duration,
+}
+
+impl LatencyConfig {
+    // Check the session's delay and every route
+    pub fn validate(&self) -> Result<(), String> {
+        self.delay.validate()?;
+        for route in &self.routes {
+            route.delay.validate()?;
+        }
+        Ok(())
+    }
+
+    // Delays for a replayed response, from the first route matching the
+    // request or else the session's delay
+    pub(crate) fn response_delay(&self, req: &Request<Bytes>, recorded: Option<&StoredLatency>) -> ResponseDelay {
+        let delay = self.routes.iter()
+            .find(|route| route.request.matches(req))
+            .map_or(&self.delay, |route| &route.delay);
+
+        match (delay, recorded) {
+            (Delay::Recorded, Some(recorded)) => ResponseDelay {
+                head: Duration::from_millis(recorded.ttfb_ms),
+                body: Duration::from_millis(recorded.total_ms.saturating_sub(recorded.ttfb_ms)),
+            },
+            // Stubs and recordings made without timing answer at once
+            (Delay::Recorded, None) => ResponseDelay::default(),
+            (delay, _) => ResponseDelay {
+                head: delay.sample(),
+                body: Duration::ZERO,
+            },
+        }
+    }
+}
+
+impl Delay {
+    // Check the bounds and percentiles serde cannot
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Delay::Uniform { min_ms, max_ms } if min_ms > max_ms => {
+                Err(format!("Uniform delay minimum {}ms exceeds its maximum {}ms", min_ms, max_ms))
+            },
+            Delay::Percentiles { points } if points.is_empty() => {
+                Err("Percentile delays need at least one point".to_string())
+            },
+            Delay::Percentiles { points } => points.iter()
+                .find(|point| !(0.0..=100.0).contains(&point.percentile))
+                .map_or(Ok(()), |point| Err(format!("Percentile {} is not between 0 and 100", point.percentile))),
+            _ => Ok(()),
+        }
+    }
+
+    // Draw a delay, recorded delays depend on the interaction and draw none
+    pub fn sample(&self) -> Duration {
+        match self {
+            Delay::None | Delay::Recorded => Duration::ZERO,
+            Delay::Fixed { ms } => Duration::from_millis(*ms),
+            Delay::Uniform { min_ms, max_ms } => Duration::from_millis(rand::thread_rng().gen_range(*min_ms..=*max_ms)),
+            Delay::Percentiles { points } => percentile_delay(points, rand::thread_rng().gen_range(0.0..100.0)),
+        }
+    }
+}
+
+// Delay at a percentile, interpolated between the points around it
+fn percentile_delay(points: &[PercentilePoint], percentile: f64) -> Duration {
+    let mut points: Vec<&PercentilePoint> = points.iter().collect();
+    points.sort_by(|a, b| a.percentile.total_cmp(&b.percentile));
+
+    let mut previous = (0.0, 0.0);
+    for point in points {
+        let current = (point.percentile, point.ms as f64);
+        if percentile <= current.0 {
+            let span = current.0 - previous.0;
+            let ratio = if span > 0.0 { (percentile - previous.0) / span } else { 1.0 };
+            return Duration::from_secs_f64((previous.1 + ratio * (current.1 - previous.1)) / 1000.0);
+        }
+        previous = current;
+    }
+
+    // Past the last point
+    Duration::from_secs_f64(previous.1 / 1000.0)
+}
+
+// Hold a body back until the delay has passed
+pub(crate) fn delay_body(body: Body, delay: Duration) -> Body {
+    if delay.is_zero() {
+        return body;
+    }
+
+    Body::new(StreamBody::new(futures_util::stream::once(async move {
+        tokio::time::sleep(delay).await;
+        BodyStream::new(body)
+    }).flatten()))
+}
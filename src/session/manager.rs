@@ -1,31 +1,72 @@
-use crate::matching::{RequestMatcher, MatchResult};
-use crate::storage::Storage;
-use crate::session::{SessionId, SessionConfig, SessionMode};
+use crate::matching::{CaptureSource, DynamicValueProcessor, RequestMatcher, MatchResult, MissCandidate};
+use crate::storage::{
+    FrameDirection, RecordedBodyFile, RecordedEvents, RecordedFrames, RecordedTrailers, SpillBuffer, Storage, StoredInteraction,
+    StoredLatency, StoredRequest, StoredResponse, StoredStub, StubRequest,
+    request_to_stored, stored_to_request, stored_to_response, stub_to_response,
+};
+use crate::session::{SessionId, validate_session_id, SessionConfig, SessionMode, SequenceExhausted, MissRecord, MissReason, JournalEntry, RequestOutcome};
+use crate::session::events::{EventRecorder, EVENT_STREAM, replay_events};
+use crate::session::latency::delay_body;
+use crate::session::websocket::{
+    FrameRecorder, accept_key, is_extension_header, is_upgrade_header, is_upgrade_request, relay_frames, replay_frames,
+};
 
 use axum::{
     body::{Bytes, Body, to_bytes},
     extract::Request,
     response::{Response},
-    http::{StatusCode, HeaderMap, Uri, Method},
+    http::{
+        StatusCode, HeaderMap, HeaderValue, Version,
+        header::{CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, TE, UPGRADE},
+    },
 };
 
-use http_body_util::{BodyExt, Full, Empty};
-use hyper::body::Incoming;
-use hyper_rustls::{HttpsConnectorBuilder};
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::rt::TokioExecutor;
+use crate::config::{AppConfig, SessionExtractor};
+use crate::template::ResponseTemplater;
+use crate::upstream::UpstreamClient;
 
-use std::collections::HashMap;
+use futures_util::StreamExt;
+use http_body_util::{BodyExt, LengthLimitError, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::upgrade::OnUpgrade;
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{RwLock, Mutex};
-use log::{debug, info, error};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, RwLock, Mutex};
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+// Header telling clients whether a replayed response came from a recording
+const MATCH_HEADER: &str = "x-translucent-match";
+
+// Session used when a request names none
+pub const DEFAULT_SESSION: &str = "default";
+
+// Number of closest interactions listed when a request matches nothing
+const MISS_CANDIDATE_LIMIT: usize = 5;
+
+// Chunks of a streamed body buffered between upstream and client
+const BODY_CHANNEL_CAPACITY: usize = 16;
+
+// Bytes of a recorded body file read at a time when it is replayed
+const BODY_FILE_CHUNK_SIZE: usize = 64 * 1024;
 
 // Session manager that handles multiple sessions
 pub struct SessionManager {
     storage: Arc<dyn Storage>,
     sessions: RwLock<HashMap<SessionId, Arc<Session>>>,
+    // Held for writing while evicted sessions are removed from storage, so a
+    // session is never restored or created while its storage goes away.
+    // Taken before sessions
+    storage_cleanup: RwLock<()>,
     app_config: Option<crate::config::AppConfig>,
+    client: Arc<UpstreamClient>,
+    templater: Arc<ResponseTemplater>,
+    session_extractors: Vec<SessionExtractor>,
 }
 
 struct Session {
@@ -33,18 +74,182 @@ struct Session {
     config: RwLock<SessionConfig>,
     matcher: Arc<RequestMatcher>,
     storage: Arc<dyn Storage>,
+    // Recorded values and the live values dynamic-value rules generated for them
     dynamic_values: RwLock<HashMap<String, String>>,
+    // Recorded values and the live values captures correlated them with, the
+    // only ones live requests are translated back from
+    captured_values: RwLock<HashMap<String, String>>,
     last_access: Mutex<Instant>,
+    client: Arc<UpstreamClient>,
+    templater: Arc<ResponseTemplater>,
+    // Responses already replayed, keyed by the first recording of each request
+    replay_positions: Mutex<HashMap<String, usize>>,
+    // Misses journaled while the session is strict
+    misses: Mutex<Vec<MissRecord>>,
+    // Requests received, oldest first
+    journal: Mutex<VecDeque<JournalEntry>>,
+    // Recorded bodies past this size are copied to a temporary file
+    spill_threshold: usize,
+    // Directory stub body files are read from
+    body_files_path: PathBuf,
 }
 
 impl SessionManager {
     // Create a new session manager
-    pub fn new(storage: Arc<dyn Storage>, app_config: Option<crate::config::AppConfig>) -> Self {
-        Self {
+    pub fn new(storage: Arc<dyn Storage>, app_config: Option<crate::config::AppConfig>) -> Result<Self, String> {
+        // One pooled client is shared by every session
+        let proxy_config = app_config.as_ref()
+            .map(|config| config.proxy.clone())
+            .unwrap_or_default();
+        let client = Arc::new(UpstreamClient::new(&proxy_config)?);
+
+        let session_extractors = app_config.as_ref()
+            .map(|config| config.session_extractors.clone())
+            .unwrap_or_else(|| AppConfig::default().session_extractors);
+
+        let mut manager = Self {
             storage,
             sessions: RwLock::new(HashMap::new()),
+            storage_cleanup: RwLock::new(()),
             app_config,
+            client,
+            templater: Arc::new(ResponseTemplater::new()),
+            session_extractors,
+        };
+
+        // Sessions persisted before a restart come back with their configuration
+        let mut sessions = HashMap::new();
+        for (id, config) in manager.storage.list_sessions()? {
+            // Sessions recorded before configurations were persisted start
+            // from the defaults, which are kept for the next restart
+            let config = match config {
+                Some(config) => config,
+                None => {
+                    let config = SessionConfig {
+                        pinned: id == DEFAULT_SESSION,
+                        ..manager.default_session_config()
+                    };
+                    if let Err(err) = manager.storage.store_session(&id, &config) {
+                        warn!("Failed to persist the configuration of session {}: {}", id, err);
+                    }
+                    config
+                },
+            };
+            if let Err(err) = config.validate() {
+                warn!("Skipping persisted session {}: {}", id, err);
+                continue;
+            }
+            info!("Restored session {} in {:?} mode", id, config.mode);
+            sessions.insert(id.clone(), manager.new_session(id, config));
+        }
+
+        // The default session is always available, and never expires
+        if !sessions.contains_key(DEFAULT_SESSION) {
+            let config = SessionConfig {
+                pinned: true,
+                ..manager.default_session_config()
+            };
+            manager.storage.store_session(DEFAULT_SESSION, &config)?;
+            sessions.insert(DEFAULT_SESSION.to_string(), manager.new_session(DEFAULT_SESSION.to_string(), config));
+        }
+
+        *manager.sessions.get_mut() = sessions;
+
+        Ok(manager)
+    }
+
+    // Evict idle sessions in the background, as configured
+    pub fn start_expiry_task(self: &Arc<Self>) {
+        let expiry = match &self.app_config {
+            Some(config) if config.expiry.idle_ttl_secs > 0 => config.expiry.clone(),
+            _ => return,
+        };
+
+        let ttl = Duration::from_secs(expiry.idle_ttl_secs);
+        let interval = Duration::from_secs(expiry.sweep_interval_secs.max(1));
+        let manager = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                // Stop once the manager is gone
+                let Some(manager) = manager.upgrade() else { break };
+                manager.expire_idle_sessions(ttl, expiry.cleanup_storage).await;
+            }
+        });
+    }
+
+    // Evict sessions idle for longer than the TTL, pinned sessions are kept.
+    // Persisted configurations stay unless storage is cleaned up, so an
+    // evicted session comes back as it was on its next request
+    pub async fn expire_idle_sessions(&self, ttl: Duration, cleanup_storage: bool) -> Vec<SessionId> {
+        let mut expired = Vec::new();
+        let _cleanup = self.storage_cleanup.write().await;
+
+        {
+            let mut sessions = self.sessions.write().await;
+
+            for (id, session) in sessions.iter() {
+                if session.config.read().await.pinned {
+                    continue;
+                }
+
+                let idle = session.last_access.lock().await.elapsed();
+                if idle > ttl {
+                    expired.push((id.clone(), idle));
+                }
+            }
+
+            for (id, idle) in &expired {
+                sessions.remove(id);
+                info!("Evicted session {} after {}s idle", id, idle.as_secs());
+            }
+        }
+
+        // Storage is cleaned up without holding up other sessions, only
+        // restoring and creating sessions waits for it
+        if cleanup_storage {
+            for (id, _) in &expired {
+                let cleaned = self.storage.clear_interactions(id)
+                    .and_then(|_| self.storage.delete_session(id));
+                if let Err(err) = cleaned {
+                    warn!("Failed to remove storage of evicted session {}: {}", id, err);
+                }
+            }
+        }
+
+        expired.into_iter().map(|(id, _)| id).collect()
+    }
+
+    // Bring back a session from its persisted configuration, false when it
+    // has none
+    pub async fn restore_session(&self, id: &str) -> Result<bool, String> {
+        validate_session_id(id)?;
+        let _cleanup = self.storage_cleanup.read().await;
+
+        let Some(config) = self.storage.get_session(id)? else {
+            return Ok(false);
+        };
+        config.validate()?;
+
+        let mut sessions = self.sessions.write().await;
+        if !sessions.contains_key(id) {
+            info!("Restored session {} in {:?} mode", id, config.mode);
+            sessions.insert(id.to_string(), self.new_session(id.to_string(), config));
         }
+
+        Ok(true)
+    }
+
+    // Ways to tell which session a request belongs to, in order
+    pub fn session_extractors(&self) -> &[SessionExtractor] {
+        &self.session_extractors
+    }
+
+    // Whether requests for unknown sessions create them
+    pub fn auto_generate_sessions(&self) -> bool {
+        self.app_config.as_ref().is_some_and(|config| config.auto_generate_sessions)
     }
 
     // Get current session count
@@ -53,49 +258,141 @@ impl SessionManager {
         self.sessions.try_read().map(|s| s.len()).unwrap_or(0)
     }
 
+    // Whether a session is live or persisted under this ID, evicted sessions
+    // keep their ID until their storage is removed
+    pub async fn session_id_taken(&self, id: &str) -> Result<bool, String> {
+        Ok(self.session_exists(id).await || self.storage.get_session(id)?.is_some())
+    }
+
     // Check if a session exists
     pub async fn session_exists(&self, id: &str) -> bool {
         let sessions = self.sessions.read().await;
         sessions.contains_key(id)
     }
 
-    // Create a new session
+    // Build the configuration given to new sessions
+    pub fn default_session_config(&self) -> SessionConfig {
+        // Get proxy config defaults from app config if available
+        match &self.app_config {
+            Some(config) => SessionConfig {
+                mode: config.proxy.default_mode.clone(),
+                target: Some(config.proxy.default_target.clone())
+                    .filter(|target| !target.is_empty()),
+                matching: config.matching.clone(),
+                replay: config.replay.clone(),
+                dynamic_values: config.dynamic_values.clone(),
+                captures: config.captures.clone(),
+                journal_limit: config.journal_limit,
+                max_body_bytes: config.max_body_bytes,
+                pinned: false,
+            },
+            None => SessionConfig::default(),
+        }
+    }
+
+    // Create a new session with the default configuration
     pub async fn create_session(&self, id: SessionId) -> Result<(), String> {
+        self.create_session_with_config(id, self.default_session_config()).await
+    }
+
+    // Create a new session with the given configuration
+    pub async fn create_session_with_config(
+        &self,
+        id: SessionId,
+        config: SessionConfig,
+    ) -> Result<(), String> {
+        validate_session_id(&id)?;
+        config.validate()?;
+
+        let _cleanup = self.storage_cleanup.read().await;
+        if self.session_id_taken(&id).await? {
+            return Err(format!("Session {} already exists", id));
+        }
+        self.insert_new_session(id, config).await
+    }
+
+    // Add a session and persist its configuration, the caller holds storage_cleanup
+    async fn insert_new_session(&self, id: SessionId, config: SessionConfig) -> Result<(), String> {
         let mut sessions = self.sessions.write().await;
 
         if sessions.contains_key(&id) {
             return Err(format!("Session {} already exists", id));
         }
 
-        // Get proxy config defaults from app config if available
-        let default_mode = SessionMode::Record;
+        self.storage.store_session(&id, &config)?;
 
-        let default_target = match &self.app_config {
-            Some(config) => config.proxy.default_target.clone(),
-            None => String::new(),
-        };
+        let session = self.new_session(id.clone(), config);
+        sessions.insert(id, session);
 
-        // Create session config with defaults
-        let config = SessionConfig {
-            mode: default_mode,
-        };
+        Ok(())
+    }
+
+    // Copy a session's configuration, interactions and stubs into a new session
+    pub async fn clone_session(
+        &self,
+        id: &str,
+        new_id: SessionId,
+        mode: Option<SessionMode>,
+    ) -> Result<SessionConfig, String> {
+        validate_session_id(&new_id)?;
+
+        let mut config = self.get_session_config(id).await?;
+        if let Some(mode) = mode {
+            config.mode = mode;
+        }
+        // A copy is a throwaway, even when its baseline is pinned
+        config.pinned = false;
+
+        // Checked before anything is copied, an evicted session's storage is left alone
+        let _cleanup = self.storage_cleanup.read().await;
+        if self.session_id_taken(&new_id).await? {
+            return Err(format!("Session {} already exists", new_id));
+        }
+
+        // Interactions are copied first, so the new session never replays a partial copy.
+        // They are copied as stored, keeping their events, frames and latency
+        for mut interaction in self.storage.list_interactions(id)? {
+            // Body files belong to their session, the copy gets its own
+            if let Some(name) = &interaction.response.body_file {
+                let mut file = self.storage.open_body_file(id, name)?;
+                interaction.response.body_file = self.storage.store_body_file(&new_id, &mut file)?;
+            }
+            self.storage.insert_interaction(&new_id, &interaction)?;
+        }
+        for stub in self.storage.list_stubs(id)? {
+            self.storage.store_stub(&new_id, &stub)?;
+        }
+
+        self.insert_new_session(new_id, config.clone()).await?;
 
+        Ok(config)
+    }
+
+    // Build a session sharing the manager's storage and client
+    fn new_session(&self, id: SessionId, config: SessionConfig) -> Arc<Session> {
         // Create matcher
         let matcher = Arc::new(RequestMatcher::new());
 
-        // Create session
-        let session = Arc::new(Session {
-            id: id.clone(),
+        Arc::new(Session {
+            id,
             config: RwLock::new(config),
             matcher,
             storage: self.storage.clone(),
             dynamic_values: RwLock::new(HashMap::new()),
+            captured_values: RwLock::new(HashMap::new()),
             last_access: Mutex::new(Instant::now()),
-        });
-
-        sessions.insert(id, session);
-
-        Ok(())
+            client: self.client.clone(),
+            templater: self.templater.clone(),
+            replay_positions: Mutex::new(HashMap::new()),
+            misses: Mutex::new(Vec::new()),
+            journal: Mutex::new(VecDeque::new()),
+            spill_threshold: self.app_config.as_ref()
+                .map(|config| config.storage.spill_threshold_bytes)
+                .unwrap_or_else(|| AppConfig::default().storage.spill_threshold_bytes),
+            body_files_path: PathBuf::from(self.app_config.as_ref()
+                .map(|config| config.storage.body_files_path.clone())
+                .unwrap_or_else(|| AppConfig::default().storage.body_files_path)),
+        })
     }
 
     // Delete a session
@@ -106,6 +403,8 @@ impl SessionManager {
             return Err(format!("Session {} not found", id));
         }
 
+        self.storage.delete_session(id)?;
+
         Ok(())
     }
 
@@ -138,13 +437,167 @@ impl SessionManager {
 
         if let Some(session) = sessions.get(id) {
             let mut config = session.config.write().await;
-            update_fn(&mut config)?;
+            let mut updated = config.clone();
+            update_fn(&mut updated)?;
+
+            self.storage.store_session(id, &updated)?;
+            *config = updated;
+
+            // A reconfigured session replays every sequence from the start
+            session.replay_positions.lock().await.clear();
             Ok(())
         } else {
             Err(format!("Session {} not found", id))
         }
     }
 
+    // Get the misses journaled by a strict session
+    pub async fn get_misses(&self, id: &str) -> Result<Vec<MissRecord>, String> {
+        let sessions = self.sessions.read().await;
+
+        if let Some(session) = sessions.get(id) {
+            Ok(session.misses.lock().await.clone())
+        } else {
+            Err(format!("Session {} not found", id))
+        }
+    }
+
+    // Clear the misses journaled by a session
+    pub async fn clear_misses(&self, id: &str) -> Result<(), String> {
+        let sessions = self.sessions.read().await;
+
+        if let Some(session) = sessions.get(id) {
+            session.misses.lock().await.clear();
+            Ok(())
+        } else {
+            Err(format!("Session {} not found", id))
+        }
+    }
+
+    // Get the requests a session received
+    pub async fn get_journal(&self, id: &str) -> Result<Vec<JournalEntry>, String> {
+        let sessions = self.sessions.read().await;
+
+        if let Some(session) = sessions.get(id) {
+            Ok(session.journal.lock().await.iter().cloned().collect())
+        } else {
+            Err(format!("Session {} not found", id))
+        }
+    }
+
+    // Count the journaled requests that meet a matcher, and have the given outcome if any
+    pub async fn count_requests(
+        &self,
+        id: &str,
+        matcher: &StubRequest,
+        outcome: Option<RequestOutcome>,
+    ) -> Result<usize, String> {
+        let mut count = 0;
+        for entry in self.get_journal(id).await? {
+            if outcome.is_some_and(|outcome| outcome != entry.outcome) {
+                continue;
+            }
+            if matcher.matches(&stored_to_request(&entry.request)?) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Clear the requests journaled by a session
+    pub async fn clear_journal(&self, id: &str) -> Result<(), String> {
+        let sessions = self.sessions.read().await;
+
+        if let Some(session) = sessions.get(id) {
+            session.journal.lock().await.clear();
+            Ok(())
+        } else {
+            Err(format!("Session {} not found", id))
+        }
+    }
+
+    // List the interactions recorded by a session
+    pub async fn list_interactions(&self, id: &str) -> Result<Vec<StoredInteraction>, String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.list_interactions(id)
+    }
+
+    // Get one interaction of a session
+    pub async fn get_interaction(&self, id: &str, interaction_id: &str) -> Result<Option<StoredInteraction>, String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.get_interaction(id, interaction_id)
+    }
+
+    // Replace one interaction of a session
+    pub async fn update_interaction(&self, id: &str, interaction: StoredInteraction) -> Result<StoredInteraction, String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.update_interaction(id, &interaction)?;
+        self.reset_replay_positions(id).await;
+
+        Ok(interaction)
+    }
+
+    // Remove one interaction from a session
+    pub async fn delete_interaction(&self, id: &str, interaction_id: &str) -> Result<(), String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.delete_interaction(id, interaction_id)?;
+        self.reset_replay_positions(id).await;
+
+        Ok(())
+    }
+
+    // Replay every sequence of a session from the start, once its recordings changed
+    async fn reset_replay_positions(&self, id: &str) {
+        let sessions = self.sessions.read().await;
+
+        if let Some(session) = sessions.get(id) {
+            session.replay_positions.lock().await.clear();
+        }
+    }
+
+    // Add a stub to a session
+    pub async fn create_stub(&self, id: &str, stub: StoredStub) -> Result<StoredStub, String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        stub.response.validate()?;
+        self.storage.store_stub(id, &stub)?;
+
+        Ok(stub)
+    }
+
+    // List the stubs of a session
+    pub async fn list_stubs(&self, id: &str) -> Result<Vec<StoredStub>, String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.list_stubs(id)
+    }
+
+    // Remove a stub from a session
+    pub async fn delete_stub(&self, id: &str, stub_id: &str) -> Result<(), String> {
+        if !self.session_exists(id).await {
+            return Err(format!("Session {} not found", id));
+        }
+
+        self.storage.delete_stub(id, stub_id)
+    }
+
     // Process a request through the appropriate session
     pub async fn process_request(
         &self,
@@ -159,9 +612,8 @@ impl SessionManager {
 
         match session {
             Some(session) => {
-                // Update last access time
-                let mut last_access = session.last_access.lock().await;
-                *last_access = Instant::now();
+                // Update last access time, without holding the lock for the whole request
+                *session.last_access.lock().await = Instant::now();
 
                 // Process request in session
                 session.process_request(req).await
@@ -180,6 +632,359 @@ fn is_hop_by_hop_header(header: &str) -> bool {
     )
 }
 
+// Read the whole request body into memory, None when it is larger than the limit
+async fn buffer_request(req: Request, limit: usize) -> Result<Option<Request<Bytes>>, String> {
+    let (parts, body) = req.into_parts();
+
+    let body_bytes = match to_bytes(body, limit).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) if std::error::Error::source(&e).is_some_and(|source| source.is::<LengthLimitError>()) => {
+            return Ok(None);
+        },
+        Err(e) => return Err(format!("Failed to read request body: {}", e)),
+    };
+
+    Ok(Some(Request::from_parts(parts, body_bytes)))
+}
+
+// Method, URI and headers of a request whose body is streamed
+fn request_head(req: &Request) -> Result<Request<Bytes>, String> {
+    let mut head = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .body(Bytes::new())
+        .map_err(|e| format!("Failed to copy request: {}", e))?;
+    *head.headers_mut() = req.headers().clone();
+    Ok(head)
+}
+
+// Read a whole upstream body and its trailers, giving up when a frame takes too long
+async fn read_body(mut body: Incoming, read_timeout: Duration) -> Result<(Bytes, Option<HeaderMap>), String> {
+    let mut bytes = Vec::new();
+    let mut trailers = None;
+
+    loop {
+        let frame = match tokio::time::timeout(read_timeout, body.frame()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(_) => return Err("Timed out reading the upstream response body".to_string()),
+        };
+        let frame = frame.map_err(|e| format!("Error reading body frame: {}", e))?;
+        match frame.into_data() {
+            Ok(data) => bytes.extend_from_slice(&data),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+
+    Ok((Bytes::from(bytes), trailers))
+}
+
+// Body sending the given bytes, followed by trailers when there are some
+fn body_with_trailers(bytes: Bytes, trailers: Option<HeaderMap>) -> Body {
+    match trailers {
+        Some(trailers) => Body::new(StreamBody::new(futures_util::stream::iter([
+            Ok::<_, Infallible>(Frame::data(bytes)),
+            Ok(Frame::trailers(trailers)),
+        ]))),
+        None => Body::from(bytes),
+    }
+}
+
+// Body streaming a recorded body file, followed by trailers when there are some
+fn file_body(file: std::fs::File, trailers: Option<HeaderMap>) -> Body {
+    let chunks = futures_util::stream::try_unfold(tokio::fs::File::from_std(file), |mut file| async move {
+        let mut chunk = vec![0u8; BODY_FILE_CHUNK_SIZE];
+        let read = file.read(&mut chunk).await?;
+        chunk.truncate(read);
+        Ok::<_, std::io::Error>((read > 0).then(|| (Frame::data(Bytes::from(chunk)), file)))
+    });
+    let trailers = futures_util::stream::iter(trailers.map(|trailers| Ok(Frame::trailers(trailers))));
+
+    Body::new(StreamBody::new(chunks.chain(trailers)))
+}
+
+// Forward an upstream body to the client chunk by chunk while recording it.
+// The last chunk is held back until the interaction is stored, so a client
+// that read the whole body can rely on the recording. Event streams are sent
+// on as they come and end with the client or the upstream, keeping the events
+// seen so far. They may pause for long, so they have no read timeout
+async fn relay_body(
+    mut body: Incoming,
+    tx: mpsc::Sender<Result<Frame<Bytes>, std::io::Error>>,
+    read_timeout: Option<Duration>,
+    mut recording: Option<PendingRecording>,
+) {
+    let mut held: Option<Bytes> = None;
+    let mut trailers: Option<HeaderMap> = None;
+    let hold_last = recording.as_ref().is_some_and(|pending| pending.events.is_none());
+    let mut failed = false;
+
+    loop {
+        let next = match read_timeout {
+            Some(read_timeout) => tokio::time::timeout(read_timeout, body.frame()).await
+                .map_err(|_| "Timed out reading the upstream response body".to_string()),
+            None => Ok(body.frame().await),
+        };
+        let frame = match next {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(None) => break,
+            Ok(Some(Err(e))) => {
+                let _ = tx.send(Err(std::io::Error::other(format!("Error reading body frame: {}", e)))).await;
+                failed = true;
+                break;
+            },
+            Err(err) => {
+                let _ = tx.send(Err(std::io::Error::other(err))).await;
+                failed = true;
+                break;
+            },
+        };
+
+        let data = match frame.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                trailers = frame.into_trailers().ok();
+                continue;
+            },
+        };
+
+        if let Some(pending) = &mut recording {
+            if let Err(err) = pending.write(&data) {
+                warn!("[Session: {}] Not recording {}: {}", pending.session_id, pending.request.uri(), err);
+                recording = None;
+            }
+        }
+
+        if !hold_last {
+            if tx.send(Ok(Frame::data(data))).await.is_err() {
+                break;
+            }
+            continue;
+        }
+
+        if let Some(previous) = held.replace(data) {
+            // Keep reading for the recording even once the client is gone
+            if tx.send(Ok(Frame::data(previous))).await.is_err() && recording.is_none() {
+                return;
+            }
+        }
+    }
+
+    // Only event streams are worth keeping when the upstream fails midway
+    if failed {
+        recording = recording.filter(|pending| pending.events.is_some());
+    }
+
+    if let Some(mut pending) = recording {
+        pending.trailers = trailers.clone();
+        if let Err(err) = pending.finish() {
+            error!("Failed to store streamed interaction: {}", err);
+        }
+    }
+
+    if failed {
+        return;
+    }
+
+    if let Some(last) = held {
+        let _ = tx.send(Ok(Frame::data(last))).await;
+    }
+
+    if let Some(trailers) = trailers {
+        let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+    }
+}
+
+// Relay an upgraded WebSocket connection between client and upstream,
+// storing the recording with its frames once either side closes
+async fn relay_websocket(client: OnUpgrade, upstream: OnUpgrade, mut recording: Option<PendingRecording>) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            warn!("WebSocket upgrade failed: {}", e);
+            return;
+        },
+    };
+
+    let relayed = relay_frames(client, upstream, |direction, data| {
+        if let Some(pending) = &mut recording {
+            if let Err(err) = pending.write_frames(direction, data) {
+                warn!("[Session: {}] Not recording {}: {}", pending.session_id, pending.request.uri(), err);
+                recording = None;
+            }
+        }
+    }).await;
+
+    if let Err(err) = relayed {
+        debug!("WebSocket relay ended: {}", err);
+    }
+
+    if let Some(pending) = recording {
+        if let Err(err) = pending.finish() {
+            error!("Failed to store WebSocket interaction: {}", err);
+        }
+    }
+}
+
+// Work out how a request was answered from the session mode and the response
+fn request_outcome(mode: &SessionMode, response: &Response) -> RequestOutcome {
+    let matched = response.headers().get(MATCH_HEADER).and_then(|value| value.to_str().ok());
+
+    match (mode, matched) {
+        (_, Some("hit")) => RequestOutcome::Matched,
+        (_, Some("stub")) => RequestOutcome::Stubbed,
+        // Misses name their reason in the header
+        (_, Some(_)) | (SessionMode::Replay, None) => RequestOutcome::Missed,
+        (SessionMode::Record | SessionMode::RecordOnMiss, _) => RequestOutcome::Recorded,
+        (SessionMode::Passthrough | SessionMode::Proxy, _) => RequestOutcome::Forwarded,
+    }
+}
+
+// Replace keys of the substitutions with their values in one pass, so a
+// replacement is never replaced again. The longest key found at a position
+// wins, and a key is only replaced as a whole token: "u-1" is left alone in
+// "u-10", and "al" in "royal"
+fn substitute(text: &str, substitutions: &[(String, String)]) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+
+    while let Some(c) = text[position..].chars().next() {
+        let (before, rest) = text.split_at(position);
+        let found = substitutions.iter().find(|(from, _)| {
+            let Some(after) = rest.strip_prefix(from.as_str()) else { return false };
+            let starts_token = !from.starts_with(is_word) || !before.ends_with(is_word);
+            let ends_token = !from.ends_with(is_word) || !after.starts_with(is_word);
+            starts_token && ends_token
+        });
+
+        match found {
+            Some((from, to)) => {
+                result.push_str(to);
+                position += from.len();
+            },
+            None => {
+                result.push(c);
+                position += c.len_utf8();
+            },
+        }
+    }
+
+    result
+}
+
+// Whether two recordings are of the same request, headers aside
+fn same_request(a: &StoredRequest, b: &StoredRequest) -> bool {
+    a.method == b.method && a.uri == b.uri && a.body == b.body
+}
+
+// Order substitutions longest first, leaving out ones that change nothing
+fn substitution_order(pairs: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut substitutions: Vec<(String, String)> = pairs
+        .filter(|(from, to)| !from.is_empty() && from != to)
+        .collect();
+    substitutions.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+    substitutions
+}
+
+// Copy headers, leaving out hop-by-hop headers
+fn end_to_end_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut result = HeaderMap::new();
+
+    for (name, value) in headers {
+        if !is_hop_by_hop_header(name.as_str()) {
+            result.append(name.clone(), value.clone());
+        }
+    }
+
+    result
+}
+
+// Interaction waiting for its response body before it is stored
+struct PendingRecording {
+    storage: Arc<dyn Storage>,
+    session_id: SessionId,
+    request: Request<Bytes>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: SpillBuffer,
+    trailers: Option<HeaderMap>,
+    max_body_bytes: usize,
+    // When the request was sent upstream and how long its response head took
+    started: Instant,
+    first_byte: Duration,
+    // Set when the response is a server-sent event stream
+    events: Option<EventRecorder>,
+    // Set when the response upgraded the connection to a WebSocket
+    frames: Option<FrameRecorder>,
+}
+
+impl PendingRecording {
+    // Copy a chunk of the response body
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.body.len() + data.len() > self.max_body_bytes {
+            return Err(format!("response body exceeds {} bytes", self.max_body_bytes));
+        }
+        if let Some(events) = &mut self.events {
+            events.push(data);
+        }
+        self.body.write(data)
+    }
+
+    // Copy bytes one side of a WebSocket sent
+    fn write_frames(&mut self, direction: FrameDirection, data: &[u8]) -> Result<(), String> {
+        let Some(frames) = &mut self.frames else { return Ok(()) };
+
+        frames.push(direction, data)?;
+        if frames.payload_bytes() > self.max_body_bytes {
+            return Err(format!("frames exceed {} bytes", self.max_body_bytes));
+        }
+        Ok(())
+    }
+
+    // Store the interaction once the whole body was copied
+    fn finish(mut self) -> Result<(), String> {
+        // Bodies spilled to disk are kept as files where the storage has them
+        let body_file = match self.body.spill_file()? {
+            Some(spilled) => self.storage.store_body_file(&self.session_id, spilled)?,
+            None => None,
+        };
+        let body = if body_file.is_some() {
+            Bytes::new()
+        } else {
+            if self.body.is_spilled() {
+                debug!("[Session: {}] Reading back {} bytes spilled to disk to store them", self.session_id, self.body.len());
+            }
+            self.body.into_bytes()?
+        };
+
+        let mut response = Response::builder()
+            .status(self.status)
+            .body(body)
+            .map_err(|e| format!("Failed to create response: {}", e))?;
+        *response.headers_mut() = self.headers;
+        if let Some(name) = body_file {
+            response.extensions_mut().insert(RecordedBodyFile(name));
+        }
+        if let Some(events) = self.events {
+            response.extensions_mut().insert(RecordedEvents(events.finish()));
+        }
+        if let Some(frames) = self.frames {
+            response.extensions_mut().insert(RecordedFrames(frames.finish()));
+        }
+        if let Some(trailers) = self.trailers {
+            response.extensions_mut().insert(RecordedTrailers(trailers));
+        }
+        response.extensions_mut().insert(StoredLatency {
+            ttfb_ms: self.first_byte.as_millis() as u64,
+            total_ms: self.started.elapsed().as_millis() as u64,
+        });
+
+        self.storage.store_interaction(&self.session_id, &self.request, &response)
+            .map_err(|e| format!("Failed to store interaction: {}", e))
+    }
+}
+
 impl Session {
     // Process a request in this session
     async fn process_request(
@@ -189,18 +994,72 @@ impl Session {
         // Get session config
         let config = self.config.read().await.clone();
 
-        match config.mode {
-            SessionMode::Record => {
-                self.record_request(req, &config).await
+        // Forwarded requests stream their body upstream as it arrives and are
+        // journaled without it. The other modes need it for matching and recording
+        let (journaled, result) = match config.mode {
+            SessionMode::Passthrough | SessionMode::Proxy => {
+                let journaled = request_to_stored(&request_head(&req)?, &[])?;
+                let result = match config.mode {
+                    SessionMode::Proxy => self.proxy_request(req, &config).await,
+                    _ => self.passthrough_request(req, &config).await,
+                };
+                (journaled, result)
+            },
+            SessionMode::Record | SessionMode::Replay | SessionMode::RecordOnMiss => {
+                let req = match buffer_request(req, config.max_body_bytes).await? {
+                    Some(req) => req,
+                    None => {
+                        warn!("[Session: {}] Request body exceeds {} bytes", self.id, config.max_body_bytes);
+                        return Response::builder()
+                            .status(StatusCode::PAYLOAD_TOO_LARGE)
+                            .body(Body::from(format!("Request body exceeds {} bytes", config.max_body_bytes)))
+                            .map_err(|e| format!("Failed to build response: {}", e));
+                    },
+                };
+                let journaled = request_to_stored(&req, &[])?;
+                let result = match config.mode {
+                    SessionMode::Replay => self.replay_request(req, &config).await,
+                    SessionMode::RecordOnMiss => self.record_on_miss_request(req, &config).await,
+                    _ => self.record_request(req, &config).await,
+                };
+                (journaled, result)
             },
-            SessionMode::Replay => self.replay_request(req).await,
+        };
+
+        let outcome = match &result {
+            Ok(response) => request_outcome(&config.mode, response),
+            Err(_) => RequestOutcome::Failed,
+        };
+        self.journal_request(journaled, outcome, &config).await;
+
+        result
+    }
+
+    // Add a request to the journal, dropping the oldest past the limit
+    async fn journal_request(&self, request: StoredRequest, outcome: RequestOutcome, config: &SessionConfig) {
+        if config.journal_limit == 0 {
+            return;
+        }
+
+        let mut journal = self.journal.lock().await;
+        while journal.len() >= config.journal_limit {
+            journal.pop_front();
         }
+
+        journal.push_back(JournalEntry {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            request,
+            outcome,
+        });
     }
 
     // Record a request and its response
     async fn record_request(
         &self,
-        req: Request,
+        req: Request<Bytes>,
         config: &SessionConfig,
     ) -> Result<Response, String> {
         // Get target URL from the request or config
@@ -208,7 +1067,8 @@ impl Session {
             .ok_or_else(|| "No target URL available for request".to_string())?;
 
         // Process the request and save the interaction
-        self.handle_http_request(req, &target_url, true).await
+        let (parts, body) = req.into_parts();
+        self.handle_http_request(Request::from_parts(parts, Body::from(body.clone())), &target_url, Some(body), config).await
     }
 
     // Pass through a request without recording
@@ -222,13 +1082,14 @@ impl Session {
             .ok_or_else(|| "No target URL available for request".to_string())?;
 
         // Process the request without saving
-        self.handle_http_request(req, &target_url, false).await
+        self.handle_http_request(req, &target_url, None, config).await
     }
 
     // Proxy a request
     async fn proxy_request(
         &self,
         req: Request,
+        config: &SessionConfig,
     ) -> Result<Response, String> {
         // Extract request parts to check headers
         let (parts, body) = req.into_parts();
@@ -241,55 +1102,395 @@ impl Session {
         let req = Request::from_parts(parts, body);
 
         // Process the request without saving
-        self.handle_http_request(req, &target_url, false).await
+        self.handle_http_request(req, &target_url, None, config).await
     }
 
     // Replay a request from stored interactions
     async fn replay_request(
         &self,
-        req: Request,
+        req_with_bytes: Request<Bytes>,
+        config: &SessionConfig,
     ) -> Result<Response, String> {
-        // Extract the request parts and body
-        let (parts, body) = req.into_parts();
+        let dynamic = DynamicValueProcessor::from_rules(&config.dynamic_values, HashMap::new());
 
-        // Read the body bytes
-        let body_bytes = to_bytes(body, 1024 * 1024 * 10)
-            .await
-            .map_err(|e| format!("Failed to read request body: {}", e))?;
+        // Match on the recorded counterparts of correlated values
+        let correlated = self.correlate_request(&req_with_bytes).await?;
 
-        // Reconstruct the request with the bytes body
-        let req_with_bytes = Request::builder()
-            .method(parts.method)
-            .uri(parts.uri)
-            .body(body_bytes)
-            .map_err(|e| format!("Failed to recreate request with bytes body: {}", e))?;
+        // Stubs with a positive priority are preferred over recordings
+        let stub = self.matcher.match_stub(&correlated, &self.id, &self.storage).await?;
+        if let Some(stub) = stub.as_ref().filter(|stub| stub.priority > 0) {
+            return self.replay_stub(&req_with_bytes, stub, config).await;
+        }
 
         // Try to match the request
-        let match_result = self.matcher.match_request(&req_with_bytes, &self.id, &self.storage).await
+        let match_result = self.matcher.match_request(&correlated, &self.id, &self.storage, &config.matching, &dynamic).await
             .map_err(|e| format!("Failed to match request: {}", e))?;
 
-        match match_result {
-            MatchResult::Match(resp) => {
+        match (match_result, stub) {
+            (MatchResult::Match(matches), _) => {
                 // We found a match, return it
-                let (parts, bytes) = resp.into_parts();
-                let body = Body::from(bytes);
-                let converted_resp = Response::from_parts(parts, body);
-                Ok(converted_resp)
+                self.replay_match(&req_with_bytes, &matches, config).await
+            },
+            // Stubs without a positive priority are fallbacks
+            (MatchResult::NoMatch, Some(stub)) => self.replay_stub(&req_with_bytes, &stub, config).await,
+            (MatchResult::NoMatch, None) => {
+                // No match found, explain which interactions came closest
+                let candidates = self.matcher.explain_miss(
+                    &correlated, &self.id, &self.storage, &config.matching, &dynamic, MISS_CANDIDATE_LIMIT,
+                ).await?;
+
+                self.miss_response(&req_with_bytes, config, MissReason::Miss, candidates).await
             },
-            MatchResult::NoMatch => {
-                // No match found
-                let response = Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::from(Bytes::from("No matching interaction found")))
-                    .unwrap();
-
-                Ok(response)
+        }
+    }
+
+    // Replay a stored interaction, or record a new one when nothing matches
+    async fn record_on_miss_request(
+        &self,
+        req_with_bytes: Request<Bytes>,
+        config: &SessionConfig,
+    ) -> Result<Response, String> {
+        let dynamic = DynamicValueProcessor::from_rules(&config.dynamic_values, HashMap::new());
+        let correlated = self.correlate_request(&req_with_bytes).await?;
+
+        let stub = self.matcher.match_stub(&correlated, &self.id, &self.storage).await?;
+        if let Some(stub) = stub.as_ref().filter(|stub| stub.priority > 0) {
+            return self.replay_stub(&req_with_bytes, stub, config).await;
+        }
+
+        let match_result = self.matcher.match_request(&correlated, &self.id, &self.storage, &config.matching, &dynamic).await
+            .map_err(|e| format!("Failed to match request: {}", e))?;
+
+        match (match_result, stub) {
+            (MatchResult::Match(matches), _) => self.replay_match(&req_with_bytes, &matches, config).await,
+            (MatchResult::NoMatch, Some(stub)) => self.replay_stub(&req_with_bytes, &stub, config).await,
+            (MatchResult::NoMatch, None) => {
+                debug!("[Session: {}] No stored interaction matched, recording a new one", self.id);
+                self.record_request(req_with_bytes, config).await
+            },
+        }
+    }
+
+    // Replay the next response of a group of matching interactions
+    async fn replay_match(
+        &self,
+        req: &Request<Bytes>,
+        matches: &[StoredInteraction],
+        config: &SessionConfig,
+    ) -> Result<Response, String> {
+        // Tied matches may be recordings of different requests, only repeats of
+        // the earliest one's request form its sequence
+        let matches: Vec<&StoredInteraction> = matches.iter()
+            .filter(|interaction| same_request(&interaction.request, &matches[0].request))
+            .collect();
+
+        let mut positions = self.replay_positions.lock().await;
+        let position = positions.entry(matches[0].id.clone()).or_insert(0);
+
+        let index = if *position < matches.len() {
+            *position
+        } else {
+            match config.replay.on_exhausted {
+                SequenceExhausted::RepeatLast => matches.len() - 1,
+                SequenceExhausted::Cycle => *position % matches.len(),
+                SequenceExhausted::Error => {
+                    drop(positions);
+
+                    // Every match is listed, none of them failed a criterion
+                    let candidates = matches.iter()
+                        .map(|interaction| MissCandidate {
+                            interaction_id: interaction.id.clone(),
+                            method: interaction.request.method.clone(),
+                            uri: interaction.request.uri.clone(),
+                            failures: Vec::new(),
+                        })
+                        .collect();
+
+                    return self.miss_response(req, config, MissReason::Exhausted, candidates).await;
+                },
+            }
+        };
+        *position += 1;
+        drop(positions);
+
+        debug!("[Session: {}] Replaying interaction {} ({} of {})",
+               self.id, matches[index].id, index + 1, matches.len());
+
+        let delay = config.replay.latency.response_delay(req, matches[index].response.latency.as_ref());
+        tokio::time::sleep(delay.head).await;
+
+        // Recorded WebSockets accept the upgrade and play their frames back
+        if matches[index].response.status == StatusCode::SWITCHING_PROTOCOLS.as_u16() && is_upgrade_request(req.headers()) {
+            return self.replay_websocket(req, &matches[index].response, config);
+        }
+
+        // Recorded event streams are replayed as recorded, with their timing
+        let events = &matches[index].response.events;
+        if !events.is_empty() {
+            let mut response = stored_to_response(&matches[index].response)?
+                .map(|_| replay_events(events.clone(), config.replay.event_timing, config.replay.event_speed));
+            response.headers_mut().remove(CONTENT_LENGTH);
+            response.headers_mut().insert(MATCH_HEADER, HeaderValue::from_static("hit"));
+            return Ok(response);
+        }
+
+        // Bodies kept in a file are too large to rewrite, they stream back as recorded
+        if let Some(name) = &matches[index].response.body_file {
+            let file = self.storage.open_body_file(&self.id, name)?;
+            let mut response = stored_to_response(&matches[index].response)?;
+            response.headers_mut().insert(MATCH_HEADER, HeaderValue::from_static("hit"));
+
+            let trailers = response.extensions_mut().remove::<RecordedTrailers>().map(|recorded| recorded.0);
+            return Ok(response.map(|_| delay_body(file_body(file, trailers), delay.body)));
+        }
+
+        let response = stored_to_response(&matches[index].response)?;
+        let mut response = self.rewrite_dynamic_values(response, config).await?;
+
+        // Templates see the live request, after recorded dynamic values were replaced
+        if config.replay.templating {
+            response = self.templater.render_response(response, req)?;
+        }
+
+        self.learn_captures(req, matches[index], response.body(), config).await;
+
+        response.headers_mut().insert(MATCH_HEADER, HeaderValue::from_static("hit"));
+
+        let trailers = response.extensions_mut().remove::<RecordedTrailers>().map(|recorded| recorded.0);
+        Ok(response.map(|body| delay_body(body_with_trailers(body, trailers), delay.body)))
+    }
+
+    // Accept a WebSocket upgrade, playing the recorded frames back once the
+    // connection is upgraded
+    fn replay_websocket(
+        &self,
+        req: &Request<Bytes>,
+        recorded: &StoredResponse,
+        config: &SessionConfig,
+    ) -> Result<Response, String> {
+        let on_upgrade = req.extensions().get::<OnUpgrade>().cloned()
+            .ok_or_else(|| "Connection cannot be upgraded".to_string())?;
+        let key = req.headers().get(SEC_WEBSOCKET_KEY)
+            .ok_or_else(|| "Upgrade request has no Sec-WebSocket-Key".to_string())?;
+        let accept = HeaderValue::from_str(&accept_key(key.as_bytes()))
+            .map_err(|e| format!("Invalid accept key: {}", e))?;
+
+        let mut response = stored_to_response(recorded)?.map(|_| Body::empty());
+        response.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+        response.headers_mut().insert(SEC_WEBSOCKET_ACCEPT, accept);
+        response.headers_mut().insert(UPGRADE, HeaderValue::from_static("websocket"));
+        response.headers_mut().insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        response.headers_mut().insert(MATCH_HEADER, HeaderValue::from_static("hit"));
+
+        let session_id = self.id.clone();
+        let frames = recorded.frames.clone();
+        let replay = config.replay.clone();
+        let max_payload = config.max_body_bytes;
+        tokio::spawn(async move {
+            let replayed = match on_upgrade.await {
+                Ok(upgraded) => replay_frames(upgraded, frames, &replay, max_payload).await,
+                Err(e) => Err(format!("WebSocket upgrade failed: {}", e)),
+            };
+            if let Err(err) = replayed {
+                warn!("[Session: {}] {}", session_id, err);
+            }
+        });
+
+        Ok(response)
+    }
+
+    // Answer a request with a stub
+    async fn replay_stub(
+        &self,
+        req: &Request<Bytes>,
+        stub: &StoredStub,
+        config: &SessionConfig,
+    ) -> Result<Response, String> {
+        debug!("[Session: {}] Answering with stub {}", self.id, stub.id);
+
+        // Stubs were never recorded, only configured delays apply
+        let delay = config.replay.latency.response_delay(req, None);
+        tokio::time::sleep(delay.head).await;
+
+        let mut response = stub_to_response(&stub.response, &self.body_files_path)?;
+
+        if config.replay.templating {
+            response = self.templater.render_response(response, req)?;
+        }
+
+        response.headers_mut().insert(MATCH_HEADER, HeaderValue::from_static("stub"));
+
+        Ok(response.map(Body::from))
+    }
+
+    // Remember the live counterpart of every value captured from the replayed interaction
+    async fn learn_captures(
+        &self,
+        req: &Request<Bytes>,
+        interaction: &StoredInteraction,
+        body: &[u8],
+        config: &SessionConfig,
+    ) {
+        if config.captures.is_empty() {
+            return;
+        }
+
+        let mut values = self.captured_values.write().await;
+
+        for capture in &config.captures {
+            let (recorded, live) = match capture.source {
+                CaptureSource::Request => (&interaction.request.body[..], &req.body()[..]),
+                CaptureSource::Response => (&interaction.response.body[..], body),
+            };
+
+            if let (Some(recorded), Some(live)) = (capture.extract(recorded), capture.extract(live)) {
+                if recorded != live {
+                    debug!("[Session: {}] Capture {} correlates {} with {}", self.id, capture.name, recorded, live);
+                    values.insert(recorded, live);
+                }
+            }
+        }
+    }
+
+    // Swap live values the session has captured back to their recorded
+    // counterparts, so the request matches what was recorded. Generated values
+    // are left alone, a counter's "1" says nothing about a "1" in a request
+    async fn correlate_request(&self, req: &Request<Bytes>) -> Result<Request<Bytes>, String> {
+        let substitutions = {
+            let values = self.captured_values.read().await;
+            substitution_order(values.iter().map(|(recorded, live)| (live.clone(), recorded.clone())))
+        };
+
+        let (uri, body) = if substitutions.is_empty() {
+            (req.uri().clone(), req.body().clone())
+        } else {
+            let uri = substitute(&req.uri().to_string(), &substitutions)
+                .parse()
+                .map_err(|e| format!("Invalid correlated URI: {}", e))?;
+            let body = match std::str::from_utf8(req.body()) {
+                Ok(text) => Bytes::from(substitute(text, &substitutions)),
+                Err(_) => req.body().clone(),
+            };
+            (uri, body)
+        };
+
+        let mut correlated = Request::builder()
+            .method(req.method().clone())
+            .uri(uri)
+            .version(req.version())
+            .body(body)
+            .map_err(|e| format!("Failed to build correlated request: {}", e))?;
+        *correlated.headers_mut() = req.headers().clone();
+
+        Ok(correlated)
+    }
+
+    // Replace dynamic values in a replayed body, the same recorded value gets
+    // the same replacement for the lifetime of the session
+    async fn rewrite_dynamic_values(
+        &self,
+        response: Response<Bytes>,
+        config: &SessionConfig,
+    ) -> Result<Response<Bytes>, String> {
+        let (mut parts, body) = response.into_parts();
+
+        let text = match std::str::from_utf8(&body) {
+            Ok(text) => text,
+            Err(_) => return Ok(Response::from_parts(parts, body)),
+        };
+
+        let Some(rewritten) = self.rewrite_text(text, config).await? else {
+            return Ok(Response::from_parts(parts, body));
+        };
+
+        // The body length may have changed
+        parts.headers.remove(CONTENT_LENGTH);
+
+        Ok(Response::from_parts(parts, Bytes::from(rewritten)))
+    }
+
+    // Replace dynamic and captured values in replayed text, None when the
+    // session has none
+    async fn rewrite_text(&self, text: &str, config: &SessionConfig) -> Result<Option<String>, String> {
+        let captured = self.captured_values.read().await;
+
+        // Generated values are only read unless rules may generate new ones
+        let (rewritten, mut values) = if config.dynamic_values.is_empty() {
+            let values = self.dynamic_values.read().await;
+            if values.is_empty() && captured.is_empty() {
+                return Ok(None);
+            }
+            (text.to_string(), values.clone())
+        } else {
+            let mut values = self.dynamic_values.write().await;
+
+            let mut processor = DynamicValueProcessor::from_rules(&config.dynamic_values, std::mem::take(&mut *values));
+            let rewritten = processor.process_request(text);
+            *values = processor.into_values();
+
+            (rewritten, values.clone())
+        };
+
+        // Both are replaced wherever they appear, captured values win
+        values.extend(captured.iter().map(|(recorded, live)| (recorded.clone(), live.clone())));
+        let substitutions = substitution_order(values.into_iter());
+
+        Ok(Some(substitute(&rewritten, &substitutions)))
+    }
+
+    // Answer a request that stored interactions cannot serve
+    async fn miss_response(
+        &self,
+        req: &Request<Bytes>,
+        config: &SessionConfig,
+        reason: MissReason,
+        candidates: Vec<MissCandidate>,
+    ) -> Result<Response, String> {
+        let record = MissRecord {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            reason,
+            candidates,
+        };
+
+        warn!("[Session: {}] Could not replay {} {} ({})", self.id, record.method, record.uri, reason.as_str());
+
+        let message = match reason {
+            MissReason::Miss => "No matching interaction found",
+            MissReason::Exhausted => "All recorded responses for this request were already replayed",
+        };
+
+        let body = json!({
+            "error": message,
+            "reason": reason,
+            "request": {
+                "method": record.method,
+                "uri": record.uri,
             },
+            "candidates": record.candidates,
+        });
+
+        if config.replay.strict {
+            self.misses.lock().await.push(record);
         }
+
+        let status = StatusCode::from_u16(config.replay.no_match_status)
+            .map_err(|e| format!("Invalid no-match status: {}", e))?;
+
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(MATCH_HEADER, reason.as_str())
+            .body(Body::from(body.to_string()))
+            .map_err(|e| format!("Failed to build response: {}", e))
     }
 
     // Helper method to extract target URL from request and config
-    fn extract_target_url(&self, req: &Request) -> Option<String> {
+    fn extract_target_url<B>(&self, req: &Request<B>) -> Option<String> {
         // Check for X-Proxy-Target header first
         if let Some(target) = req.headers().get("X-Proxy-Target") {
             if let Ok(target_str) = target.to_str() {
@@ -297,11 +1498,12 @@ impl Session {
             }
         }
 
-        // If we have a target_url in config and it's not empty, use it
-        let config = match self.config.try_read() {
-            Ok(config) => config,
-            Err(_) => return None,
-        };
+        // If we have a target in config, use it
+        if let Ok(config) = self.config.try_read() {
+            if let Some(target) = &config.target {
+                return Some(target.clone());
+            }
+        }
 
         // Try to extract from Host header
         if let Some(host) = req.headers().get("Host") {
@@ -331,10 +1533,11 @@ impl Session {
         }
 
         // Try to get from config
-        let config = match self.config.try_read() {
-            Ok(config) => config,
-            Err(_) => return None,
-        };
+        if let Ok(config) = self.config.try_read() {
+            if let Some(target) = &config.target {
+                return Some(target.clone());
+            }
+        }
 
         // Extract from the Host header
         if let Some(host) = parts.headers.get("Host") {
@@ -353,24 +1556,20 @@ impl Session {
         None
     }
 
-    // Unified HTTP/HTTPS request handler
-    // Unified HTTP/HTTPS request handler
+    // Unified HTTP/HTTPS request handler, the interaction is recorded when the
+    // request body to record it with is given
     async fn handle_http_request(
         &self,
         req: Request,
         target_url: &str,
-        save_interaction: bool,
+        recorded_body: Option<Bytes>,
+        config: &SessionConfig,
     ) -> Result<Response, String> {
         // Extract parts from the request
         let (parts, body) = req.into_parts();
         let method = parts.method.clone();
         let uri = parts.uri.clone();
 
-        // Read the body bytes
-        let body_bytes = to_bytes(body, 1024 * 1024 * 10)
-            .await
-            .map_err(|e| format!("Failed to read request body: {}", e))?;
-
         // Construct the forward URL
         let query_str = match uri.query() {
             Some(q) => format!("?{}", q),
@@ -390,118 +1589,171 @@ impl Session {
         let target_uri: hyper::Uri = forward_url.parse()
             .map_err(|e| format!("Invalid forward URL: {}", e))?;
 
-        // Create request builder
+        // Create request builder, requests that arrived over HTTP/2 such as
+        // gRPC calls also go upstream over HTTP/2
         let mut request_builder = hyper::Request::builder()
             .method(method.clone())
             .uri(target_uri.clone());
+        if parts.version == Version::HTTP_2 {
+            request_builder = request_builder.version(Version::HTTP_2);
+        }
+
+        // WebSocket upgrades keep the hop-by-hop headers asking for them
+        let websocket = is_upgrade_request(&parts.headers);
 
         // Add headers, filtering out session headers and hop-by-hop headers
         for (name, value) in &parts.headers {
             let header_name = name.as_str();
-            if !header_name.starts_with("x-session") && !is_hop_by_hop_header(header_name) {
+            let forwarded = !is_hop_by_hop_header(header_name)
+                || (websocket && is_upgrade_header(header_name))
+                // gRPC servers expect to be told the client accepts trailers
+                || (name == TE && value == "trailers");
+            // Frames are recorded as sent, so no WebSocket extension may change them
+            let extension = websocket && is_extension_header(header_name);
+            // The client's Host names the simulator, without it the client
+            // fills in the target's authority from the URI
+            let host = name == HOST && !self.client.forward_host_header();
+            if !header_name.starts_with("x-session") && forwarded && !extension && !host {
                 request_builder = request_builder.header(name, value);
             }
         }
 
         // Build request with the body
         let hyper_request = request_builder
-            .body(Full::new(Bytes::copy_from_slice(&body_bytes)))
+            .body(body)
             .map_err(|e| format!("Failed to build request: {}", e))?;
 
         // Create and send request with our client
         debug!("[Session: {}] Sending request to target", self.id);
-        let response = self.create_client_and_send_request(hyper_request).await?;
+        let started = Instant::now();
+        let mut response = self.create_client_and_send_request(hyper_request).await?;
+        let first_byte = started.elapsed();
+
+        // The client's connection is handed to the upstream once both are upgraded
+        let upgrade = if websocket && response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let client = parts.extensions.get::<OnUpgrade>().cloned()
+                .ok_or_else(|| "Connection cannot be upgraded".to_string())?;
+            Some((client, hyper::upgrade::on(&mut response)))
+        } else {
+            None
+        };
 
         // Extract status and headers
         let (resp_parts, resp_body) = response.into_parts();
         let status = resp_parts.status;
-        let headers = resp_parts.headers;
 
         debug!("[Session: {}] Received response with status: {}", self.id, status);
 
-        // Read the response body correctly using the frame API
-        let mut resp_bytes_vec = Vec::new();
-        let mut resp_body = resp_body;
-
-        while let Some(frame) = resp_body.frame().await {
-            let frame = frame.map_err(|e| format!("Error reading body frame: {}", e))?;
-            if let Some(data) = frame.data_ref() {
-                resp_bytes_vec.extend_from_slice(data);
-            }
-        }
-
-        let resp_bytes = Bytes::from(resp_bytes_vec);
+        // Hop-by-hop headers only apply to the upstream connection
+        let response_headers = end_to_end_headers(&resp_parts.headers);
 
-        // If we need to save this interaction for recording
-        if save_interaction {
-            debug!("[Session: {}] Saving interaction for future replay", self.id);
+        let event_stream = response_headers.get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(EVENT_STREAM));
 
-            // Recreate the request for storage
-            let stored_req = Request::builder()
+        // Recreate the request for storage
+        let recording = if let Some(body_bytes) = recorded_body {
+            let mut stored_req = Request::builder()
                 .method(method)
                 .uri(uri)
-                .body(Bytes::from(body_bytes))
+                .body(body_bytes)
                 .map_err(|e| format!("Failed to recreate request: {}", e))?;
+            *stored_req.headers_mut() = end_to_end_headers(&parts.headers);
+
+            Some(PendingRecording {
+                storage: self.storage.clone(),
+                session_id: self.id.clone(),
+                request: stored_req,
+                status,
+                headers: response_headers.clone(),
+                body: SpillBuffer::new(self.spill_threshold),
+                trailers: None,
+                max_body_bytes: config.max_body_bytes,
+                started,
+                first_byte,
+                events: event_stream.then(EventRecorder::new),
+                frames: upgrade.is_some().then(|| FrameRecorder::new(config.max_body_bytes)),
+            })
+        } else {
+            None
+        };
 
-            // Create response for storage
-            let stored_resp = Response::builder()
-                .status(status.clone())
-                .body(Bytes::from(resp_bytes.clone()))
-                .map_err(|e| format!("Failed to create response: {}", e))?;
+        if let Some((client, upstream)) = upgrade {
+            tokio::spawn(relay_websocket(client, upstream, recording));
+
+            let mut response = Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .map_err(|e| format!("Failed to build response: {}", e))?;
+            *response.headers_mut() = response_headers;
+            for name in [UPGRADE, CONNECTION] {
+                if let Some(value) = resp_parts.headers.get(&name) {
+                    response.headers_mut().insert(name, value.clone());
+                }
+            }
 
-            // Store the interaction
-            self.storage.store_interaction(&self.id, &stored_req, &stored_resp)
-                .map_err(|e| format!("Failed to store interaction: {}", e))?;
+            return Ok(response);
         }
 
-        // Build and return the response
-        let mut response_builder = Response::builder().status(status);
-
-        // Add headers to the response
-        for (name, value) in headers {
-            // Check if the name is Some and not a hop-by-hop header
-            if let Some(header_name) = name {
-                if !is_hop_by_hop_header(header_name.as_str()) {
-                    response_builder = response_builder.header(header_name, value);
+        // Small bodies of known length gain nothing from streaming, they are
+        // read whole so the interaction is stored before the client is answered.
+        // Event streams are always relayed as they arrive
+        let content_length = response_headers.get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        let body = if !event_stream && content_length.is_some_and(|length| length <= self.spill_threshold) {
+            let (resp_bytes, trailers) = read_body(resp_body, self.client.read_timeout()).await?;
+
+            if let Some(mut recording) = recording {
+                // Like streamed bodies, bodies past the session limit are answered but not recorded
+                match recording.write(&resp_bytes) {
+                    Ok(()) => {
+                        debug!("[Session: {}] Saving interaction for future replay", self.id);
+                        recording.trailers = trailers.clone();
+                        recording.finish()?;
+                    },
+                    Err(err) => warn!("[Session: {}] Not recording {}: {}", self.id, recording.request.uri(), err),
                 }
             }
-        }
 
-        let response = response_builder
-            .body(Body::from(resp_bytes))
+            body_with_trailers(resp_bytes, trailers)
+        } else {
+            // Stream the body to the client, copying it into the recording as it goes
+            let (tx, rx) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+            let read_timeout = (!event_stream).then(|| self.client.read_timeout());
+            tokio::spawn(relay_body(resp_body, tx, read_timeout, recording));
+
+            Body::new(StreamBody::new(futures_util::stream::unfold(rx, |mut rx| async move {
+                rx.recv().await.map(|frame| (frame, rx))
+            })))
+        };
+
+        // Build and return the response
+        let mut response = Response::builder()
+            .status(status)
+            .body(body)
             .map_err(|e| format!("Failed to build response: {}", e))?;
+        *response.headers_mut() = response_headers;
 
         Ok(response)
     }
 
-    // Create a client that handles both HTTP and HTTPS
-    // Create a client that handles HTTP (without HTTPS for now)
+    // Send a request through the shared upstream client
     async fn create_client_and_send_request(
         &self,
-        req: hyper::Request<Full<Bytes>>,
+        req: hyper::Request<Body>,
     ) -> Result<hyper::Response<Incoming>, String> {
-        // Create an HTTP connector
-        let mut http = HttpConnector::new();
-        http.enforce_http(false); // Allow HTTPS schema in URLs, but will connect over HTTP
-
         // Log the request target
         debug!("[Session: {}] Sending request to: {}", self.id, req.uri());
 
-        // Create a client with HTTP support only
-        let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
-            .build(http);
-
-        // Send the request
-        match client.request(req).await {
+        match self.client.send(req).await {
             Ok(response) => Ok(response),
             Err(e) => {
                 // Log detailed error
                 error!("[Session: {}] Failed to send proxy request: {}", self.id, e);
-                Err(format!("Failed to send request: {}", e))
+                Err(e)
             }
         }
     }
-
-    // TODO: Later, we can add proper HTTPS support with appropriate error handling
-    // once we resolve the dependency issues or version compatibility.
 }
\ No newline at end of file
@@ -1,5 +1,12 @@
+mod events;
+mod latency;
 mod manager;
 mod models;
+mod websocket;
 
-pub use manager::SessionManager;
-pub use models::{SessionId, SessionMode, SessionConfig};
\ No newline at end of file
+pub use manager::{SessionManager, DEFAULT_SESSION};
+pub use models::{
+    SessionId, validate_session_id, SessionMode, SessionConfig, ReplayConfig, SequenceExhausted, EventTiming, FrameReplay, MissRecord, MissReason, RequestOutcome, JournalEntry,
+    LatencyConfig, RouteDelay, Delay, PercentilePoint,
+};
+pub(crate) use models::{default_journal_limit, default_max_body_bytes};
\ No newline at end of file
@@ -1,18 +1,321 @@
+use crate::matching::{CaptureRule, DynamicValueRule, MatchConfig, MissCandidate};
+use crate::storage::{StoredRequest, StubRequest};
+use axum::http::StatusCode;
+use std::path::{Component, Path};
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 
 pub type SessionId = String;
 
+// Session IDs name a directory of the storage, anything but a single plain
+// path component could reach outside it
+pub fn validate_session_id(id: &str) -> Result<(), String> {
+    let mut components = Path::new(id).components();
+    let single = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+    if !single || id.contains(['/', '\\']) {
+        return Err(format!("Invalid session ID {}", id));
+    }
+    Ok(())
+}
+
 // Operation modes for a session
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum SessionMode {
+    // Forward upstream and store every interaction
     Record,
+    // Answer from stored interactions only
     Replay,
+    // Forward upstream without recording
+    Passthrough,
+    // Forward to the target named by the request itself, without recording
+    Proxy,
+    // Replay when a stored interaction matches, otherwise forward upstream and record
+    RecordOnMiss,
+}
+
+// A replayed request that could not be answered from stored interactions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissRecord {
+    pub timestamp: u64,
+    pub method: String,
+    pub uri: String,
+    pub reason: MissReason,
+    pub candidates: Vec<MissCandidate>,
+}
+
+// Why a replayed request could not be answered
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissReason {
+    // No stored interaction matched
+    Miss,
+    // Every recorded response for the request was already replayed
+    Exhausted,
+}
+
+impl MissReason {
+    // Value of the match header and of the reason field of miss bodies
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MissReason::Miss => "miss",
+            MissReason::Exhausted => "exhausted",
+        }
+    }
+}
+
+// How a session answered a request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RequestOutcome {
+    // Replayed from a recorded interaction
+    Matched,
+    // Answered by a stub
+    Stubbed,
+    // Forwarded upstream and recorded
+    Recorded,
+    // Forwarded upstream without recording
+    Forwarded,
+    // Nothing recorded could answer it
+    Missed,
+    // Processing failed
+    Failed,
+}
+
+// A request received by a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: u64,
+    pub request: StoredRequest,
+    pub outcome: RequestOutcome,
 }
 
 // Session configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionConfig {
     pub mode: SessionMode,
+    // Upstream base URL, e.g. https://api.example.com
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub matching: MatchConfig,
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    // Volatile values normalized before matching and regenerated in replayed bodies
+    #[serde(default)]
+    pub dynamic_values: Vec<DynamicValueRule>,
+    // Values correlated between recorded and live interactions
+    #[serde(default)]
+    pub captures: Vec<CaptureRule>,
+    // Most requests kept in the journal, the oldest are dropped first
+    #[serde(default = "default_journal_limit")]
+    pub journal_limit: usize,
+    // Largest request body buffered for matching or recording, and largest
+    // response body recorded. Forwarded requests are streamed whatever their size
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    // Pinned sessions are never evicted for being idle
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+pub(crate) fn default_journal_limit() -> usize {
+    1000
+}
+
+pub(crate) fn default_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+// What to do once every recorded response for a request has been replayed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SequenceExhausted {
+    // Keep returning the last recorded response
+    RepeatLast,
+    // Start again from the first recorded response
+    Cycle,
+    // Answer with an error
+    Error,
+}
+
+// Replay behavior of a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    #[serde(default = "default_sequence_exhausted")]
+    pub on_exhausted: SequenceExhausted,
+    // Status returned when no stored interaction matches, e.g. 599 to stand out from upstream errors
+    #[serde(default = "default_no_match_status")]
+    pub no_match_status: u16,
+    // Keep a journal of every miss so tests can assert it is empty
+    #[serde(default)]
+    pub strict: bool,
+    // Render replayed bodies and headers as templates referencing the live request
+    #[serde(default)]
+    pub templating: bool,
+    // Spacing of the server-sent events and WebSocket frames of replayed streams
+    #[serde(default = "default_event_timing")]
+    pub event_timing: EventTiming,
+    // How much faster than recorded events and frames are replayed, 2.0 halves every delay
+    #[serde(default = "default_event_speed")]
+    pub event_speed: f64,
+    #[serde(default = "default_frame_replay")]
+    pub frame_replay: FrameReplay,
+    // Delay before replayed responses and stubs are sent
+    #[serde(default)]
+    pub latency: LatencyConfig,
+}
+
+// Latency added to replayed responses, per session and per route
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyConfig {
+    #[serde(default)]
+    pub delay: Delay,
+    // Delays for the requests a route matches, the first matching route wins
+    // over the session's delay
+    #[serde(default)]
+    pub routes: Vec<RouteDelay>,
+}
+
+// Delay given to the requests a route matches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteDelay {
+    #[serde(flatten)]
+    pub request: StubRequest,
+    pub delay: Delay,
+}
+
+// How long to wait before answering
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Delay {
+    // Answer at once
+    #[default]
+    None,
+    // Send the head after the recorded time to first byte and finish the body
+    // after the recorded total duration
+    Recorded,
+    Fixed { ms: u64 },
+    // Any delay between the bounds, equally likely
+    Uniform { min_ms: u64, max_ms: u64 },
+    // A distribution given by some of its percentiles, interpolated between
+    // them, from no delay at the 0th percentile
+    Percentiles { points: Vec<PercentilePoint> },
+}
+
+// Delay below which a percentage of responses fall
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PercentilePoint {
+    pub percentile: f64,
+    pub ms: u64,
+}
+
+// What sends the server frames of a replayed WebSocket
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum FrameReplay {
+    // The recorded timeline, from the moment the connection is upgraded
+    Timeline,
+    // A client message equal to the one that preceded them in the recording
+    ClientFrames,
+}
+
+// How the server-sent events of a recorded stream are replayed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum EventTiming {
+    // With the recorded spacing, scaled by the event speed
+    Original,
+    // All at once
+    Immediate,
+}
+
+fn default_event_timing() -> EventTiming {
+    EventTiming::Original
+}
+
+fn default_event_speed() -> f64 {
+    1.0
+}
+
+fn default_frame_replay() -> FrameReplay {
+    FrameReplay::Timeline
+}
+
+fn default_sequence_exhausted() -> SequenceExhausted {
+    SequenceExhausted::RepeatLast
+}
+
+fn default_no_match_status() -> u16 {
+    404
+}
+
+// Default implementation for ReplayConfig
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            on_exhausted: default_sequence_exhausted(),
+            no_match_status: default_no_match_status(),
+            strict: false,
+            templating: false,
+            event_timing: default_event_timing(),
+            event_speed: default_event_speed(),
+            frame_replay: default_frame_replay(),
+            latency: LatencyConfig::default(),
+        }
+    }
+}
+
+impl SessionConfig {
+    // Apply a JSON merge patch (RFC 7386) to this configuration
+    pub fn apply_patch(&mut self, patch: &Value) -> Result<(), String> {
+        let mut current = serde_json::to_value(&*self)
+            .map_err(|e| format!("Failed to serialize session config: {}", e))?;
+
+        merge_patch(&mut current, patch);
+
+        let updated: SessionConfig = serde_json::from_value(current)
+            .map_err(|e| format!("Invalid session config: {}", e))?;
+        updated.validate()?;
+
+        *self = updated;
+
+        Ok(())
+    }
+
+    // Check the parts of the configuration serde cannot
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.replay.event_speed > 0.0 && self.replay.event_speed.is_finite()) {
+            return Err("event_speed must be a positive number".to_string());
+        }
+        StatusCode::from_u16(self.replay.no_match_status)
+            .map_err(|_| format!("no_match_status {} is not a valid HTTP status", self.replay.no_match_status))?;
+        self.replay.latency.validate()?;
+        for capture in &self.captures {
+            capture.validate()?;
+        }
+        Ok(())
+    }
+}
+
+// Merge a JSON patch into a value, null removes a field
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch_obj = match patch {
+        Value::Object(patch_obj) => patch_obj,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+
+    if let Value::Object(target_obj) = target {
+        for (key, value) in patch_obj {
+            if value.is_null() {
+                target_obj.remove(key);
+            } else {
+                merge_patch(target_obj.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
 }
 
 // Default implementation for SessionConfig
@@ -20,6 +323,14 @@ impl Default for SessionConfig {
     fn default() -> Self {
         Self {
             mode: SessionMode::Record,
+            target: None,
+            matching: MatchConfig::default(),
+            replay: ReplayConfig::default(),
+            dynamic_values: Vec::new(),
+            captures: Vec::new(),
+            journal_limit: default_journal_limit(),
+            max_body_bytes: default_max_body_bytes(),
+            pinned: false,
         }
     }
 }
\ No newline at end of file
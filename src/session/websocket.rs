@@ -0,0 +1,388 @@
+use crate::session::{EventTiming, FrameReplay, ReplayConfig};
+use crate::storage::{FrameDirection, FrameKind, StoredFrame};
+
+use axum::http::{HeaderMap, header::UPGRADE};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Appended to the client key before hashing it into the accept key (RFC 6455)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Bytes read from either side of a connection at a time
+const READ_BUFFER_SIZE: usize = 8192;
+
+// Whether a request asks to upgrade its connection to a WebSocket
+pub(crate) fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+// Hop-by-hop headers an upgrade needs to pass on
+pub(crate) fn is_upgrade_header(header: &str) -> bool {
+    header.eq_ignore_ascii_case("connection") || header.eq_ignore_ascii_case("upgrade")
+}
+
+// Extensions such as permessage-deflate change what frames carry, so they are
+// never negotiated: recorded frames stay plain and replay as they were
+pub(crate) fn is_extension_header(header: &str) -> bool {
+    header.eq_ignore_ascii_case("sec-websocket-extensions")
+}
+
+// Sec-WebSocket-Accept value answering a client's Sec-WebSocket-Key
+pub(crate) fn accept_key(key: &[u8]) -> String {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key);
+    context.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(context.finish())
+}
+
+// A frame read off the wire, unmasked
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+    // Bytes the frame took on the wire
+    length: usize,
+}
+
+// Read the first frame of a buffer, None until it is complete. Frames
+// declaring more than max_payload bytes are refused before they are buffered
+fn parse_frame(data: &[u8], max_payload: usize) -> Result<Option<Frame>, String> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+
+    // Reserved bits are only set by extensions, and none are negotiated
+    if data[0] & 0x70 != 0 {
+        return Err("WebSocket frame uses reserved bits".to_string());
+    }
+
+    let fin = data[0] & 0x80 != 0;
+    let opcode = data[0] & 0x0f;
+    let masked = data[1] & 0x80 != 0;
+
+    let (payload_length, mut offset) = match data[1] & 0x7f {
+        126 if data.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+        127 if data.len() < 10 => return Ok(None),
+        127 => {
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&data[2..10]);
+            let length = usize::try_from(u64::from_be_bytes(length))
+                .map_err(|_| "WebSocket frame too large".to_string())?;
+            (length, 10)
+        },
+        length => (length as usize, 2),
+    };
+    if payload_length > max_payload {
+        return Err(format!("WebSocket frame exceeds {} bytes", max_payload));
+    }
+
+    let mask = if masked {
+        if data.len() < offset + 4 {
+            return Ok(None);
+        }
+        let mask = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    if data.len() - offset < payload_length {
+        return Ok(None);
+    }
+
+    let mut payload = data[offset..offset + payload_length].to_vec();
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok(Some(Frame { fin, opcode, payload, length: offset + payload_length }))
+}
+
+// Build an unmasked frame, as servers send them
+fn encode_frame(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+    let opcode = match kind {
+        FrameKind::Text => 0x1,
+        FrameKind::Binary => 0x2,
+        FrameKind::Close => 0x8,
+        FrameKind::Ping => 0x9,
+        FrameKind::Pong => 0xa,
+    };
+
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+// Splits what one side of a connection sends into messages
+struct FrameReader {
+    buffer: Vec<u8>,
+    // Message whose final fragment has not arrived yet
+    fragments: Option<(FrameKind, Vec<u8>)>,
+    // Largest message kept, frames and fragments past it are an error
+    max_payload: usize,
+}
+
+impl FrameReader {
+    fn new(max_payload: usize) -> Self {
+        Self { buffer: Vec::new(), fragments: None, max_payload }
+    }
+
+    // Add bytes read from the connection, returning the messages they complete
+    fn push(&mut self, data: &[u8]) -> Result<Vec<(FrameKind, Vec<u8>)>, String> {
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+
+        while let Some(frame) = parse_frame(&self.buffer, self.max_payload)? {
+            self.buffer.drain(..frame.length);
+
+            let (kind, payload) = match frame.opcode {
+                0x0 => {
+                    let (kind, mut message) = self.fragments.take()
+                        .ok_or_else(|| "WebSocket continuation frame without a message".to_string())?;
+                    if message.len() + frame.payload.len() > self.max_payload {
+                        return Err(format!("WebSocket message exceeds {} bytes", self.max_payload));
+                    }
+                    message.extend_from_slice(&frame.payload);
+                    (kind, message)
+                },
+                0x1 => (FrameKind::Text, frame.payload),
+                0x2 => (FrameKind::Binary, frame.payload),
+                // Control frames are never fragmented
+                0x8 => (FrameKind::Close, frame.payload),
+                0x9 => (FrameKind::Ping, frame.payload),
+                0xa => (FrameKind::Pong, frame.payload),
+                opcode => return Err(format!("Unknown WebSocket opcode {:#x}", opcode)),
+            };
+
+            if frame.fin || matches!(kind, FrameKind::Close | FrameKind::Ping | FrameKind::Pong) {
+                messages.push((kind, payload));
+            } else {
+                self.fragments = Some((kind, payload));
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+// Records the messages both sides of a relayed connection send, noting when
+// each one was complete
+pub(crate) struct FrameRecorder {
+    start: Instant,
+    client: FrameReader,
+    server: FrameReader,
+    frames: Vec<StoredFrame>,
+    payload_bytes: usize,
+}
+
+impl FrameRecorder {
+    // Offsets are measured from the moment the recorder is created
+    pub(crate) fn new(max_payload: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            client: FrameReader::new(max_payload),
+            server: FrameReader::new(max_payload),
+            frames: Vec::new(),
+            payload_bytes: 0,
+        }
+    }
+
+    // Add bytes one side sent, messages may span reads
+    pub(crate) fn push(&mut self, direction: FrameDirection, data: &[u8]) -> Result<(), String> {
+        let reader = match direction {
+            FrameDirection::Client => &mut self.client,
+            FrameDirection::Server => &mut self.server,
+        };
+        let offset_ms = self.start.elapsed().as_millis() as u64;
+
+        for (kind, payload) in reader.push(data)? {
+            self.payload_bytes += payload.len();
+            self.frames.push(StoredFrame { offset_ms, direction, kind, payload });
+        }
+
+        Ok(())
+    }
+
+    // Size of all payloads recorded so far
+    pub(crate) fn payload_bytes(&self) -> usize {
+        self.payload_bytes
+    }
+
+    pub(crate) fn finish(self) -> Vec<StoredFrame> {
+        self.frames
+    }
+}
+
+// Copy bytes both ways between an upgraded client and upstream, showing each
+// chunk to observe first. Ends as soon as either side closes
+pub(crate) async fn relay_frames(
+    client: Upgraded,
+    upstream: Upgraded,
+    mut observe: impl FnMut(FrameDirection, &[u8]),
+) -> Result<(), String> {
+    let (mut client_read, mut client_write) = tokio::io::split(TokioIo::new(client));
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(TokioIo::new(upstream));
+    let mut client_buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut upstream_buffer = vec![0u8; READ_BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            read = client_read.read(&mut client_buffer) => {
+                let read = read.map_err(|e| format!("Failed to read from client: {}", e))?;
+                if read == 0 {
+                    let _ = upstream_write.shutdown().await;
+                    return Ok(());
+                }
+                observe(FrameDirection::Client, &client_buffer[..read]);
+                upstream_write.write_all(&client_buffer[..read]).await
+                    .map_err(|e| format!("Failed to write to upstream: {}", e))?;
+            },
+            read = upstream_read.read(&mut upstream_buffer) => {
+                let read = read.map_err(|e| format!("Failed to read from upstream: {}", e))?;
+                if read == 0 {
+                    let _ = client_write.shutdown().await;
+                    return Ok(());
+                }
+                observe(FrameDirection::Server, &upstream_buffer[..read]);
+                client_write.write_all(&upstream_buffer[..read]).await
+                    .map_err(|e| format!("Failed to write to client: {}", e))?;
+            },
+        }
+    }
+}
+
+// Server frames sent after the recorded client message at index, or before
+// the first one, up to the next client message
+fn replies(frames: &[StoredFrame], after: Option<usize>) -> impl Iterator<Item = &StoredFrame> {
+    frames.iter()
+        .skip(after.map_or(0, |index| index + 1))
+        .take_while(|frame| frame.direction == FrameDirection::Server)
+}
+
+// Play recorded frames back to a client. Closing and pings are answered live,
+// so what the server recorded in reply to them is left out
+pub(crate) async fn replay_frames(
+    upgraded: Upgraded,
+    frames: Vec<StoredFrame>,
+    replay: &ReplayConfig,
+    max_payload: usize,
+) -> Result<(), String> {
+    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+
+    let frames: Vec<StoredFrame> = frames.into_iter()
+        .take_while(|frame| !(frame.direction == FrameDirection::Client && frame.kind == FrameKind::Close))
+        .filter(|frame| match frame.direction {
+            FrameDirection::Client => matches!(frame.kind, FrameKind::Text | FrameKind::Binary),
+            FrameDirection::Server => frame.kind != FrameKind::Pong,
+        })
+        .collect();
+
+    let delay = |offset_ms: u64| match replay.event_timing {
+        EventTiming::Original => Duration::from_secs_f64(offset_ms as f64 / 1000.0 / replay.event_speed),
+        EventTiming::Immediate => Duration::ZERO,
+    };
+
+    // Server frames waiting to be sent, earliest first
+    let start = tokio::time::Instant::now();
+    let mut queue: VecDeque<(tokio::time::Instant, StoredFrame)> = match replay.frame_replay {
+        FrameReplay::Timeline => frames.iter()
+            .filter(|frame| frame.direction == FrameDirection::Server)
+            .map(|frame| (start + delay(frame.offset_ms), frame.clone()))
+            .collect(),
+        FrameReplay::ClientFrames => replies(&frames, None)
+            .map(|frame| (start + delay(frame.offset_ms), frame.clone()))
+            .collect(),
+    };
+
+    // Recorded client messages already answered
+    let mut answered = vec![false; frames.len()];
+    let mut client = FrameReader::new(max_payload);
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+
+    loop {
+        let next_due = queue.front().map(|(due, _)| *due);
+
+        tokio::select! {
+            read = reader.read(&mut buffer) => {
+                let read = read.map_err(|e| format!("Failed to read from client: {}", e))?;
+                if read == 0 {
+                    return Ok(());
+                }
+
+                for (kind, payload) in client.push(&buffer[..read])? {
+                    match kind {
+                        FrameKind::Close => {
+                            let _ = writer.write_all(&encode_frame(FrameKind::Close, &payload)).await;
+                            return Ok(());
+                        },
+                        FrameKind::Ping => {
+                            writer.write_all(&encode_frame(FrameKind::Pong, &payload)).await
+                                .map_err(|e| format!("Failed to write to client: {}", e))?;
+                        },
+                        FrameKind::Pong => {},
+                        FrameKind::Text | FrameKind::Binary => {
+                            if replay.frame_replay != FrameReplay::ClientFrames {
+                                continue;
+                            }
+
+                            let trigger = frames.iter().enumerate().position(|(index, frame)| {
+                                !answered[index]
+                                    && frame.direction == FrameDirection::Client
+                                    && frame.kind == kind
+                                    && frame.payload == payload
+                            });
+
+                            let Some(trigger) = trigger else {
+                                debug!("No recorded client message matches {}", String::from_utf8_lossy(&payload));
+                                continue;
+                            };
+                            answered[trigger] = true;
+
+                            let now = tokio::time::Instant::now();
+                            for frame in replies(&frames, Some(trigger)) {
+                                let due = now + delay(frame.offset_ms.saturating_sub(frames[trigger].offset_ms));
+                                let position = queue.partition_point(|(queued, _)| *queued <= due);
+                                queue.insert(position, (due, frame.clone()));
+                            }
+                        },
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(next_due.unwrap_or(start)), if next_due.is_some() => {
+                let Some((_, frame)) = queue.pop_front() else { continue };
+
+                writer.write_all(&encode_frame(frame.kind, &frame.payload)).await
+                    .map_err(|e| format!("Failed to write to client: {}", e))?;
+
+                if frame.kind == FrameKind::Close {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
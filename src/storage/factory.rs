@@ -9,8 +9,12 @@ impl StorageFactory {
     // Create a storage implementation based on config
     pub fn create_storage(config: &StorageConfig) -> Result<Arc<dyn Storage>, String> {
         match config.type_.as_str() {
-            "memory" => Ok(Arc::new(MemoryStorage::new())),
-            "filesystem" => Ok(Arc::new(FileSystemStorage::new(&config.path)?)),
+            "memory" => Ok(Arc::new(
+                MemoryStorage::new().with_header_deny_list(config.header_deny_list.clone())
+            )),
+            "filesystem" => Ok(Arc::new(
+                FileSystemStorage::new(&config.path)?.with_header_deny_list(config.header_deny_list.clone())
+            )),
             _ => Err(format!("Unknown storage type: {}", config.type_)),
         }
     }
@@ -1,4 +1,5 @@
-use crate::storage::{Storage, StoredInteraction, request_to_stored, response_to_stored, stored_to_request, stored_to_response};
+use crate::session::{SessionConfig, SessionId, validate_session_id};
+use crate::storage::{Storage, StoredInteraction, StoredStub, request_to_stored, response_to_stored, validate_id};
 use axum::{
     body::Bytes,
     extract::Request,
@@ -8,10 +9,15 @@ use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::{PathBuf};
 use uuid::Uuid;
+use log::warn;
+
+// File holding a session's configuration, next to its interactions
+const SESSION_FILE: &str = "session.json";
 
 // File system-based storage
 pub struct FileSystemStorage {
     base_path: PathBuf,
+    header_deny_list: Vec<String>,
 }
 
 impl FileSystemStorage {
@@ -26,9 +32,16 @@ impl FileSystemStorage {
 
         Ok(Self {
             base_path: path,
+            header_deny_list: Vec::new(),
         })
     }
 
+    // Set headers that are never persisted
+    pub fn with_header_deny_list(mut self, header_deny_list: Vec<String>) -> Self {
+        self.header_deny_list = header_deny_list;
+        self
+    }
+
     // Get path for a session
     fn get_session_path(&self, session_id: &str) -> PathBuf {
         let mut path = self.base_path.clone();
@@ -41,11 +54,52 @@ impl FileSystemStorage {
         &self,
         session_id: &str,
         interaction_id: &str,
-    ) -> PathBuf {
+    ) -> Result<PathBuf, String> {
+        validate_id(interaction_id)?;
+
         let mut path = self.get_session_path(session_id);
         path.push(format!("{}.json", interaction_id));
+        Ok(path)
+    }
+
+    // Get path for a session's configuration
+    fn get_session_config_path(&self, session_id: &str) -> PathBuf {
+        let mut path = self.get_session_path(session_id);
+        path.push(SESSION_FILE);
+        path
+    }
+
+    // Get path for the body files of a session
+    fn get_bodies_path(&self, session_id: &str) -> PathBuf {
+        let mut path = self.get_session_path(session_id);
+        path.push("bodies");
         path
     }
+
+    // Get path for a body file, named by a UUID like interactions
+    fn get_body_file_path(&self, session_id: &str, name: &str) -> Result<PathBuf, String> {
+        validate_id(name)?;
+
+        let mut path = self.get_bodies_path(session_id);
+        path.push(name);
+        Ok(path)
+    }
+
+    // Get path for the stubs of a session, kept apart from recordings
+    fn get_stubs_path(&self, session_id: &str) -> PathBuf {
+        let mut path = self.get_session_path(session_id);
+        path.push("stubs");
+        path
+    }
+
+    // Get path for a stub
+    fn get_stub_path(&self, session_id: &str, stub_id: &str) -> Result<PathBuf, String> {
+        validate_id(stub_id)?;
+
+        let mut path = self.get_stubs_path(session_id);
+        path.push(format!("{}.json", stub_id));
+        Ok(path)
+    }
 }
 
 impl Storage for FileSystemStorage {
@@ -56,23 +110,21 @@ impl Storage for FileSystemStorage {
         response: &Response<Bytes>,
     ) -> Result<(), String> {
         // Convert request to storable format
-        let stored_request = request_to_stored(request)
+        let stored_request = request_to_stored(request, &self.header_deny_list)
             .map_err(|e| format!("Failed to convert request: {}", e))?;
 
         // Convert response to storable format
-        let stored_response = response_to_stored(response)
+        let stored_response = response_to_stored(response, &self.header_deny_list)
             .map_err(|e| format!("Failed to convert response: {}", e))?;
 
         // Create interaction
-        let interaction = StoredInteraction {
-            id: Uuid::new_v4().to_string(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-            request: stored_request,
-            response: stored_response,
-        };
+        let interaction = StoredInteraction::new(stored_request, stored_response);
+
+        self.insert_interaction(session_id, &interaction)
+    }
+
+    fn insert_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        let interaction_path = self.get_interaction_path(session_id, &interaction.id)?;
 
         // Create session directory if it doesn't exist
         let session_path = self.get_session_path(session_id);
@@ -82,8 +134,7 @@ impl Storage for FileSystemStorage {
         }
 
         // Serialize and write to file
-        let interaction_path = self.get_interaction_path(session_id, &interaction.id);
-        let json = serde_json::to_string_pretty(&interaction)
+        let json = serde_json::to_string_pretty(interaction)
             .map_err(|e| format!("Failed to serialize interaction: {}", e))?;
 
         let mut file = File::create(interaction_path)
@@ -95,10 +146,7 @@ impl Storage for FileSystemStorage {
         Ok(())
     }
 
-    fn get_interactions(
-        &self,
-        session_id: &str,
-    ) -> Result<Vec<(Request<Bytes>, Response<Bytes>)>, String> {
+    fn list_interactions(&self, session_id: &str) -> Result<Vec<StoredInteraction>, String> {
         let session_path = self.get_session_path(session_id);
 
         // If directory doesn't exist, return empty list
@@ -116,8 +164,9 @@ impl Storage for FileSystemStorage {
             let entry = entry.map_err(|e| format!("Failed to read entry: {}", e))?;
             let path = entry.path();
 
-            // Skip non-JSON files
-            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            // Skip non-JSON files and the session configuration
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json")
+                || path.file_name().and_then(|name| name.to_str()) == Some(SESSION_FILE) {
                 continue;
             }
 
@@ -133,17 +182,62 @@ impl Storage for FileSystemStorage {
             let interaction: StoredInteraction = serde_json::from_str(&contents)
                 .map_err(|e| format!("Failed to deserialize interaction: {}", e))?;
 
-            // Convert to Request and Response
-            let request = stored_to_request(&interaction.request)
-                .map_err(|e| format!("Failed to convert request: {}", e))?;
+            result.push(interaction);
+        }
+
+        // Directory order is platform dependent, sort into recording order
+        result.sort_by(|a, b| a.recording_order().cmp(&b.recording_order()));
+
+        Ok(result)
+    }
 
-            let response = stored_to_response(&interaction.response)
-                .map_err(|e| format!("Failed to convert response: {}", e))?;
+    fn get_interaction(&self, session_id: &str, interaction_id: &str) -> Result<Option<StoredInteraction>, String> {
+        let interaction_path = self.get_interaction_path(session_id, interaction_id)?;
 
-            result.push((request, response));
+        if !interaction_path.exists() {
+            return Ok(None);
         }
 
-        Ok(result)
+        let contents = fs::read_to_string(&interaction_path)
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+
+        let interaction = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to deserialize interaction: {}", e))?;
+
+        Ok(Some(interaction))
+    }
+
+    fn update_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        let interaction_path = self.get_interaction_path(session_id, &interaction.id)?;
+
+        if !interaction_path.exists() {
+            return Err(format!("Interaction {} not found", interaction.id));
+        }
+
+        let json = serde_json::to_string_pretty(interaction)
+            .map_err(|e| format!("Failed to serialize interaction: {}", e))?;
+
+        fs::write(&interaction_path, json)
+            .map_err(|e| format!("Failed to write to file: {}", e))?;
+
+        Ok(())
+    }
+
+    fn delete_interaction(&self, session_id: &str, interaction_id: &str) -> Result<(), String> {
+        let interaction = self.get_interaction(session_id, interaction_id)?
+            .ok_or_else(|| format!("Interaction {} not found", interaction_id))?;
+
+        fs::remove_file(self.get_interaction_path(session_id, interaction_id)?)
+            .map_err(|e| format!("Failed to remove file: {}", e))?;
+
+        // The body file goes with the interaction, a missing one is already gone
+        if let Some(name) = &interaction.response.body_file {
+            if let Ok(path) = self.get_body_file_path(session_id, name) {
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        Ok(())
     }
 
     fn clear_interactions(&self, session_id: &str) -> Result<(), String> {
@@ -154,9 +248,198 @@ impl Storage for FileSystemStorage {
             return Ok(());
         }
 
-        // Remove directory and all contents
-        fs::remove_dir_all(&session_path)
-            .map_err(|e| format!("Failed to remove directory: {}", e))?;
+        // Remove everything but the session configuration
+        let config_path = self.get_session_config_path(session_id);
+        if !config_path.exists() {
+            fs::remove_dir_all(&session_path)
+                .map_err(|e| format!("Failed to remove directory: {}", e))?;
+            return Ok(());
+        }
+
+        let entries = fs::read_dir(&session_path)
+            .map_err(|e| format!("Failed to read directory: {}", e))?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Failed to read entry: {}", e))?.path();
+
+            if path == config_path {
+                continue;
+            }
+
+            let removed = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+            removed.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        }
+
+        Ok(())
+    }
+
+    fn store_body_file(&self, session_id: &str, body: &mut File) -> Result<Option<String>, String> {
+        let bodies_path = self.get_bodies_path(session_id);
+        fs::create_dir_all(&bodies_path)
+            .map_err(|e| format!("Failed to create directory: {}", e))?;
+
+        let name = Uuid::new_v4().to_string();
+        let mut file = File::create(self.get_body_file_path(session_id, &name)?)
+            .map_err(|e| format!("Failed to create file: {}", e))?;
+        std::io::copy(body, &mut file)
+            .map_err(|e| format!("Failed to write body file: {}", e))?;
+
+        Ok(Some(name))
+    }
+
+    fn open_body_file(&self, session_id: &str, name: &str) -> Result<File, String> {
+        File::open(self.get_body_file_path(session_id, name)?)
+            .map_err(|e| format!("Failed to open body file {}: {}", name, e))
+    }
+
+    fn store_session(&self, session_id: &str, config: &SessionConfig) -> Result<(), String> {
+        validate_session_id(session_id)?;
+
+        // Create session directory if it doesn't exist
+        let session_path = self.get_session_path(session_id);
+        if !session_path.exists() {
+            fs::create_dir_all(&session_path)
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+
+        let json = serde_json::to_string_pretty(config)
+            .map_err(|e| format!("Failed to serialize session: {}", e))?;
+
+        fs::write(self.get_session_config_path(session_id), json)
+            .map_err(|e| format!("Failed to write to file: {}", e))?;
+
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &str) -> Result<Option<SessionConfig>, String> {
+        validate_session_id(session_id)?;
+
+        let config_path = self.get_session_config_path(session_id);
+        if !config_path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&config_path)
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+
+        let config = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to deserialize session {}: {}", session_id, e))?;
+
+        Ok(Some(config))
+    }
+
+    fn list_sessions(&self) -> Result<Vec<(SessionId, Option<SessionConfig>)>, String> {
+        let mut result = Vec::new();
+
+        let entries = fs::read_dir(&self.base_path)
+            .map_err(|e| format!("Failed to read directory: {}", e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read entry: {}", e))?;
+            let session_id = entry.file_name().to_string_lossy().to_string();
+            if !entry.path().is_dir() || validate_session_id(&session_id).is_err() {
+                continue;
+            }
+
+            // Directories without a configuration were recorded before
+            // configurations were persisted
+            let config_path = self.get_session_config_path(&session_id);
+            if !config_path.exists() {
+                result.push((session_id, None));
+                continue;
+            }
+
+            // One unreadable session must not keep the others from coming back
+            let config = fs::read_to_string(&config_path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| serde_json::from_str::<SessionConfig>(&contents).map_err(|e| e.to_string()));
+            match config {
+                Ok(config) => result.push((session_id, Some(config))),
+                Err(err) => warn!("Skipping persisted session {}: {}", session_id, err),
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn delete_session(&self, session_id: &str) -> Result<(), String> {
+        let config_path = self.get_session_config_path(session_id);
+
+        // Nothing persisted, nothing to do
+        if !config_path.exists() {
+            return Ok(());
+        }
+
+        fs::remove_file(&config_path)
+            .map_err(|e| format!("Failed to remove file: {}", e))?;
+
+        Ok(())
+    }
+
+    fn store_stub(&self, session_id: &str, stub: &StoredStub) -> Result<(), String> {
+        let stub_path = self.get_stub_path(session_id, &stub.id)?;
+
+        // Create stubs directory if it doesn't exist
+        let stubs_path = self.get_stubs_path(session_id);
+        if !stubs_path.exists() {
+            fs::create_dir_all(&stubs_path)
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+
+        let json = serde_json::to_string_pretty(stub)
+            .map_err(|e| format!("Failed to serialize stub: {}", e))?;
+
+        fs::write(stub_path, json)
+            .map_err(|e| format!("Failed to write to file: {}", e))?;
+
+        Ok(())
+    }
+
+    fn list_stubs(&self, session_id: &str) -> Result<Vec<StoredStub>, String> {
+        let stubs_path = self.get_stubs_path(session_id);
+
+        // If directory doesn't exist, return empty list
+        if !stubs_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut result = Vec::new();
+
+        let entries = fs::read_dir(&stubs_path)
+            .map_err(|e| format!("Failed to read directory: {}", e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read entry: {}", e))?;
+            let path = entry.path();
+
+            // Skip non-JSON files
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read file: {}", e))?;
+
+            let stub: StoredStub = serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to deserialize stub: {}", e))?;
+
+            result.push(stub);
+        }
+
+        result.sort_by(|a, b| a.stub_order().cmp(&b.stub_order()));
+
+        Ok(result)
+    }
+
+    fn delete_stub(&self, session_id: &str, stub_id: &str) -> Result<(), String> {
+        let stub_path = self.get_stub_path(session_id, stub_id)?;
+
+        if !stub_path.exists() {
+            return Err(format!("Stub {} not found", stub_id));
+        }
+
+        fs::remove_file(&stub_path)
+            .map_err(|e| format!("Failed to remove file: {}", e))?;
 
         Ok(())
     }
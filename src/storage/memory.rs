@@ -1,4 +1,5 @@
-use crate::storage::{Storage, StoredInteraction, request_to_stored, response_to_stored, stored_to_request, stored_to_response};
+use crate::session::{SessionConfig, SessionId, validate_session_id};
+use crate::storage::{Storage, StoredInteraction, StoredStub, request_to_stored, response_to_stored, validate_id};
 use axum::{
     body::Bytes,
     extract::Request,
@@ -6,19 +7,37 @@ use axum::{
 };
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use uuid::Uuid;
 
 // Memory-based storage
 pub struct MemoryStorage {
     interactions: Arc<Mutex<HashMap<String, Vec<StoredInteraction>>>>,
+    stubs: Arc<Mutex<HashMap<String, Vec<StoredStub>>>>,
+    sessions: Arc<Mutex<HashMap<String, SessionConfig>>>,
+    header_deny_list: Vec<String>,
+}
+
+// Default implementation for MemoryStorage
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemoryStorage {
     pub fn new() -> Self {
         Self {
             interactions: Arc::new(Mutex::new(HashMap::new())),
+            stubs: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            header_deny_list: Vec::new(),
         }
     }
+
+    // Set headers that are never persisted
+    pub fn with_header_deny_list(mut self, header_deny_list: Vec<String>) -> Self {
+        self.header_deny_list = header_deny_list;
+        self
+    }
 }
 
 impl Storage for MemoryStorage {
@@ -29,23 +48,21 @@ impl Storage for MemoryStorage {
         response: &Response<Bytes>,
     ) -> Result<(), String> {
         // Convert request to storable format
-        let stored_request = request_to_stored(request)
+        let stored_request = request_to_stored(request, &self.header_deny_list)
             .map_err(|e| format!("Failed to convert request: {}", e))?;
 
         // Convert response to storable format
-        let stored_response = response_to_stored(response)
+        let stored_response = response_to_stored(response, &self.header_deny_list)
             .map_err(|e| format!("Failed to convert response: {}", e))?;
 
         // Create interaction
-        let interaction = StoredInteraction {
-            id: Uuid::new_v4().to_string(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-            request: stored_request,
-            response: stored_response,
-        };
+        let interaction = StoredInteraction::new(stored_request, stored_response);
+
+        self.insert_interaction(session_id, &interaction)
+    }
+
+    fn insert_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        validate_id(&interaction.id)?;
 
         // Store in memory
         let mut interactions = self.interactions.lock()
@@ -55,38 +72,66 @@ impl Storage for MemoryStorage {
             .entry(session_id.to_string())
             .or_insert_with(Vec::new);
 
-        session_interactions.push(interaction);
+        session_interactions.push(interaction.clone());
 
         Ok(())
     }
 
-    fn get_interactions(
-        &self,
-        session_id: &str,
-    ) -> Result<Vec<(Request<Bytes>, Response<Bytes>)>, String> {
+    fn list_interactions(&self, session_id: &str) -> Result<Vec<StoredInteraction>, String> {
         let interactions = self.interactions.lock()
             .map_err(|e| format!("Failed to lock interactions: {}", e))?;
 
-        let session_interactions = match interactions.get(session_id) {
-            Some(interactions) => interactions,
-            None => return Ok(Vec::new()),
-        };
+        let mut result = interactions.get(session_id).cloned().unwrap_or_default();
+        result.sort_by(|a, b| a.recording_order().cmp(&b.recording_order()));
 
-        let mut result = Vec::new();
+        Ok(result)
+    }
 
-        for interaction in session_interactions {
-            // Convert stored request to Request
-            let request = stored_to_request(&interaction.request)
-                .map_err(|e| format!("Failed to convert request: {}", e))?;
+    fn get_interaction(&self, session_id: &str, interaction_id: &str) -> Result<Option<StoredInteraction>, String> {
+        validate_id(interaction_id)?;
 
-            // Convert stored response to Response
-            let response = stored_to_response(&interaction.response)
-                .map_err(|e| format!("Failed to convert response: {}", e))?;
+        let interactions = self.interactions.lock()
+            .map_err(|e| format!("Failed to lock interactions: {}", e))?;
 
-            result.push((request, response));
+        Ok(interactions.get(session_id)
+            .and_then(|session_interactions| {
+                session_interactions.iter().find(|interaction| interaction.id == interaction_id)
+            })
+            .cloned())
+    }
+
+    fn update_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        validate_id(&interaction.id)?;
+
+        let mut interactions = self.interactions.lock()
+            .map_err(|e| format!("Failed to lock interactions: {}", e))?;
+
+        let existing = interactions.get_mut(session_id)
+            .and_then(|session_interactions| {
+                session_interactions.iter_mut().find(|existing| existing.id == interaction.id)
+            })
+            .ok_or_else(|| format!("Interaction {} not found", interaction.id))?;
+        *existing = interaction.clone();
+
+        Ok(())
+    }
+
+    fn delete_interaction(&self, session_id: &str, interaction_id: &str) -> Result<(), String> {
+        validate_id(interaction_id)?;
+
+        let mut interactions = self.interactions.lock()
+            .map_err(|e| format!("Failed to lock interactions: {}", e))?;
+
+        let session_interactions = interactions.get_mut(session_id)
+            .ok_or_else(|| format!("Interaction {} not found", interaction_id))?;
+        let count = session_interactions.len();
+        session_interactions.retain(|interaction| interaction.id != interaction_id);
+
+        if session_interactions.len() == count {
+            return Err(format!("Interaction {} not found", interaction_id));
         }
 
-        Ok(result)
+        Ok(())
     }
 
     fn clear_interactions(&self, session_id: &str) -> Result<(), String> {
@@ -95,6 +140,88 @@ impl Storage for MemoryStorage {
 
         interactions.remove(session_id);
 
+        let mut stubs = self.stubs.lock()
+            .map_err(|e| format!("Failed to lock stubs: {}", e))?;
+
+        stubs.remove(session_id);
+
+        Ok(())
+    }
+
+    fn store_session(&self, session_id: &str, config: &SessionConfig) -> Result<(), String> {
+        validate_session_id(session_id)?;
+
+        let mut sessions = self.sessions.lock()
+            .map_err(|e| format!("Failed to lock sessions: {}", e))?;
+
+        sessions.insert(session_id.to_string(), config.clone());
+
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &str) -> Result<Option<SessionConfig>, String> {
+        validate_session_id(session_id)?;
+
+        let sessions = self.sessions.lock()
+            .map_err(|e| format!("Failed to lock sessions: {}", e))?;
+
+        Ok(sessions.get(session_id).cloned())
+    }
+
+    fn list_sessions(&self) -> Result<Vec<(SessionId, Option<SessionConfig>)>, String> {
+        let sessions = self.sessions.lock()
+            .map_err(|e| format!("Failed to lock sessions: {}", e))?;
+
+        Ok(sessions.iter().map(|(id, config)| (id.clone(), Some(config.clone()))).collect())
+    }
+
+    fn delete_session(&self, session_id: &str) -> Result<(), String> {
+        let mut sessions = self.sessions.lock()
+            .map_err(|e| format!("Failed to lock sessions: {}", e))?;
+
+        sessions.remove(session_id);
+
+        Ok(())
+    }
+
+    fn store_stub(&self, session_id: &str, stub: &StoredStub) -> Result<(), String> {
+        validate_id(&stub.id)?;
+
+        let mut stubs = self.stubs.lock()
+            .map_err(|e| format!("Failed to lock stubs: {}", e))?;
+
+        stubs.entry(session_id.to_string())
+            .or_default()
+            .push(stub.clone());
+
+        Ok(())
+    }
+
+    fn list_stubs(&self, session_id: &str) -> Result<Vec<StoredStub>, String> {
+        let stubs = self.stubs.lock()
+            .map_err(|e| format!("Failed to lock stubs: {}", e))?;
+
+        let mut result = stubs.get(session_id).cloned().unwrap_or_default();
+        result.sort_by(|a, b| a.stub_order().cmp(&b.stub_order()));
+
+        Ok(result)
+    }
+
+    fn delete_stub(&self, session_id: &str, stub_id: &str) -> Result<(), String> {
+        validate_id(stub_id)?;
+
+        let mut stubs = self.stubs.lock()
+            .map_err(|e| format!("Failed to lock stubs: {}", e))?;
+
+        let session_stubs = stubs.get_mut(session_id)
+            .ok_or_else(|| format!("Stub {} not found", stub_id))?;
+        let count = session_stubs.len();
+        session_stubs.retain(|stub| stub.id != stub_id);
+
+        if session_stubs.len() == count {
+            return Err(format!("Stub {} not found", stub_id));
+        }
+
         Ok(())
     }
 }
\ No newline at end of file
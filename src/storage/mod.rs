@@ -2,25 +2,88 @@ mod memory;
 mod filesystem;
 mod factory;
 mod models;
+mod spill;
 
 pub use models::*;
 pub use factory::StorageFactory;
 pub use memory::MemoryStorage;
 pub use filesystem::FileSystemStorage;
+pub use spill::SpillBuffer;
+
+use crate::session::{SessionConfig, SessionId};
+use axum::{body::Bytes, extract::Request, response::Response};
+use std::fs::File;
+
+// Request and response pair rebuilt from a stored interaction
+pub type InteractionPair = (Request<Bytes>, Response<Bytes>);
 
 // Storage trait for different backends
 pub trait Storage: Send + Sync {
     fn store_interaction(
         &self,
         session_id: &str,
-        request: &axum::extract::Request<axum::body::Bytes>,
-        response: &axum::response::Response<axum::body::Bytes>
+        request: &Request<Bytes>,
+        response: &Response<Bytes>
     ) -> Result<(), String>;
 
-    fn get_interactions(
-        &self,
-        session_id: &str
-    ) -> Result<Vec<(axum::extract::Request<axum::body::Bytes>, axum::response::Response<axum::body::Bytes>)>, String>;
+    // Store an interaction as it is, keeping its ID, timestamp and recorded extras
+    fn insert_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String>;
+
+    // List stored interactions in recording order, oldest first
+    fn list_interactions(&self, session_id: &str) -> Result<Vec<StoredInteraction>, String>;
+
+    fn get_interactions(&self, session_id: &str) -> Result<Vec<InteractionPair>, String> {
+        self.list_interactions(session_id)?
+            .iter()
+            .map(|interaction| {
+                // Convert stored request and response back
+                let request = stored_to_request(&interaction.request)
+                    .map_err(|e| format!("Failed to convert request: {}", e))?;
+
+                let response = stored_to_response(&interaction.response)
+                    .map_err(|e| format!("Failed to convert response: {}", e))?;
+
+                Ok((request, response))
+            })
+            .collect()
+    }
+
+    fn get_interaction(&self, session_id: &str, interaction_id: &str) -> Result<Option<StoredInteraction>, String>;
+
+    // Replace a stored interaction, keyed by its ID
+    fn update_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String>;
+
+    fn delete_interaction(&self, session_id: &str, interaction_id: &str) -> Result<(), String>;
 
     fn clear_interactions(&self, session_id: &str) -> Result<(), String>;
-}
\ No newline at end of file
+
+    // Keep a body spilled to disk while recording as a file of the session,
+    // returning the name responses refer to it by. None keeps bodies inline
+    fn store_body_file(&self, _session_id: &str, _body: &mut File) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+
+    // Open a file kept by store_body_file
+    fn open_body_file(&self, _session_id: &str, name: &str) -> Result<File, String> {
+        Err(format!("Body file {} not found", name))
+    }
+
+    // Keep a session's configuration so it survives a restart
+    fn store_session(&self, session_id: &str, config: &SessionConfig) -> Result<(), String>;
+
+    // Get a session's persisted configuration, None when it has none
+    fn get_session(&self, session_id: &str) -> Result<Option<SessionConfig>, String>;
+
+    // List persisted sessions, without a configuration for sessions recorded
+    // before configurations were persisted
+    fn list_sessions(&self) -> Result<Vec<(SessionId, Option<SessionConfig>)>, String>;
+
+    fn delete_session(&self, session_id: &str) -> Result<(), String>;
+
+    fn store_stub(&self, session_id: &str, stub: &StoredStub) -> Result<(), String>;
+
+    // List stubs highest priority first, then in the order they were defined
+    fn list_stubs(&self, session_id: &str) -> Result<Vec<StoredStub>, String>;
+
+    fn delete_stub(&self, session_id: &str, stub_id: &str) -> Result<(), String>;
+}
@@ -1,13 +1,17 @@
-use serde::{Serialize, Deserialize};
+use crate::matching::{Pattern, deserialize_whole};
+use serde::{Serialize, Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::path::Path;
+use uuid::Uuid;
 use axum::{
     body::Bytes,
     extract::Request,
+    http::{HeaderMap, HeaderName, HeaderValue, header::CONTENT_TYPE},
     response::Response,
 };
 
 // Serializable interaction
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredInteraction {
     pub id: String,
     pub timestamp: u64,
@@ -15,43 +19,313 @@ pub struct StoredInteraction {
     pub response: StoredResponse,
 }
 
+impl StoredInteraction {
+    // Create a new interaction recorded now
+    pub fn new(request: StoredRequest, response: StoredResponse) -> Self {
+        Self {
+            // Version 7 IDs are time-ordered, so they also order interactions within a second
+            id: Uuid::now_v7().to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            request,
+            response,
+        }
+    }
+
+    // Ordering key used wherever interactions are listed, oldest first
+    pub fn recording_order(&self) -> (u64, &str) {
+        (self.timestamp, self.id.as_str())
+    }
+}
+
+// Hand-written interaction served without an upstream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredStub {
+    pub id: String,
+    pub timestamp: u64,
+    // Higher priorities are tried first, recorded interactions count as 0
+    pub priority: i32,
+    pub request: StubRequest,
+    pub response: StubResponse,
+}
+
+impl StoredStub {
+    // Create a new stub defined now
+    pub fn new(priority: i32, request: StubRequest, response: StubResponse) -> Self {
+        Self {
+            id: Uuid::now_v7().to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            priority,
+            request,
+            response,
+        }
+    }
+
+    // Ordering key used wherever stubs are listed, highest priority first
+    pub fn stub_order(&self) -> (std::cmp::Reverse<i32>, u64, &str) {
+        (std::cmp::Reverse(self.priority), self.timestamp, self.id.as_str())
+    }
+}
+
+// Requests a stub answers, every criterion that is set must hold
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StubRequest {
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    // The whole path has to match, compiled when the stub is loaded
+    #[serde(default, deserialize_with = "deserialize_whole")]
+    pub path_regex: Option<Pattern>,
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // JSON the request body must contain
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+}
+
+// Response a stub answers with, the body is either inline or read from body_file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StubResponse {
+    #[serde(default = "default_stub_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // Strings are sent as they are, other JSON values are serialized
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+    #[serde(default)]
+    pub body_file: Option<String>,
+}
+
+fn default_stub_status() -> u16 {
+    200
+}
+
 // Serializable request
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredRequest {
     pub method: String,
     pub uri: String,
-    pub headers: HashMap<String, Vec<String>>,
+    pub headers: StoredHeaders,
     pub body: Vec<u8>,
 }
 
 // Serializable response
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredResponse {
     pub status: u16,
-    pub headers: HashMap<String, Vec<String>>,
+    pub headers: StoredHeaders,
     pub body: Vec<u8>,
+    // Name of the storage file holding bodies spilled to disk while recording,
+    // body is then empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<String>,
+    // Trailers sent after the body, such as the grpc-status of gRPC responses
+    #[serde(default, skip_serializing_if = "StoredHeaders::is_empty")]
+    pub trailers: StoredHeaders,
+    // Server-sent events with their timing, the body holds them all concatenated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<StoredEvent>,
+    // WebSocket messages exchanged once the response upgraded the connection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub frames: Vec<StoredFrame>,
+    // Time the upstream took to answer, also a response extension on its way to storage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<StoredLatency>,
+}
+
+// Upstream timing of a recorded response
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StoredLatency {
+    // Until the response head arrived
+    pub ttfb_ms: u64,
+    // Until the whole body arrived, or the connection closed for streams
+    pub total_ms: u64,
+}
+
+// One server-sent event of a streamed response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredEvent {
+    // Time since the response started
+    pub offset_ms: u64,
+    // The raw event, including its terminating blank line
+    pub data: String,
+}
+
+// Response extension carrying the events of a recorded stream to storage
+#[derive(Debug, Clone)]
+pub struct RecordedEvents(pub Vec<StoredEvent>);
+
+// Response extension carrying the trailers of a response to and from storage
+#[derive(Debug, Clone)]
+pub struct RecordedTrailers(pub HeaderMap);
+
+// Response extension naming the storage file a recorded body was kept in
+#[derive(Debug, Clone)]
+pub struct RecordedBodyFile(pub String);
+
+// One WebSocket message, fragments are joined into a single message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredFrame {
+    // Time since the connection was upgraded
+    pub offset_ms: u64,
+    pub direction: FrameDirection,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+// Which side of a WebSocket connection sent a frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FrameDirection {
+    Client,
+    Server,
+}
+
+// Type of a WebSocket message
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FrameKind {
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+// Response extension carrying the frames of a recorded WebSocket to storage
+#[derive(Debug, Clone)]
+pub struct RecordedFrames(pub Vec<StoredFrame>);
+
+// Serializable header value, raw bytes are kept for values that are not valid UTF-8
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StoredHeaderValue {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl StoredHeaderValue {
+    // Convert a header value, keeping its exact bytes
+    pub fn from_header_value(value: &HeaderValue) -> Self {
+        match value.to_str() {
+            Ok(text) => StoredHeaderValue::Text(text.to_string()),
+            Err(_) => StoredHeaderValue::Binary(value.as_bytes().to_vec()),
+        }
+    }
+
+    // Get the raw bytes of the value
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            StoredHeaderValue::Text(text) => text.as_bytes(),
+            StoredHeaderValue::Binary(bytes) => bytes,
+        }
+    }
+}
+
+// Ordered list of headers, repeated headers are kept as separate entries
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct StoredHeaders(pub Vec<(String, StoredHeaderValue)>);
+
+impl StoredHeaders {
+    // Convert a header map, skipping every header in the deny-list
+    pub fn from_header_map(headers: &HeaderMap, deny_list: &[String]) -> Self {
+        let entries = headers.iter()
+            .filter(|(name, _)| !deny_list.iter().any(|denied| denied.eq_ignore_ascii_case(name.as_str())))
+            .map(|(name, value)| (name.to_string(), StoredHeaderValue::from_header_value(value)))
+            .collect();
+
+        StoredHeaders(entries)
+    }
+
+    // Convert back into a header map
+    pub fn to_header_map(&self) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &self.0 {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::from_bytes(value.as_bytes())
+                .map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+
+            headers.append(name, value);
+        }
+
+        Ok(headers)
+    }
+
+    // Check if there are no headers
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Get the first value of a header
+    pub fn get(&self, name: &str) -> Option<&StoredHeaderValue> {
+        self.0.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    // Get every value of a header in recorded order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a StoredHeaderValue> + 'a {
+        self.0.iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredHeaders {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Recordings made before headers were ordered store them as a map
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            List(Vec<(String, StoredHeaderValue)>),
+            Map(HashMap<String, Vec<String>>),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::List(entries) => StoredHeaders(entries),
+            Repr::Map(map) => StoredHeaders(
+                map.into_iter()
+                    .flat_map(|(name, values)| {
+                        values.into_iter()
+                            .map(move |value| (name.clone(), StoredHeaderValue::Text(value)))
+                    })
+                    .collect(),
+            ),
+        })
+    }
+}
+
+// Interaction, stub and body file IDs are UUIDs, anything else could name a
+// file outside the session directory. Every storage checks them the same way
+pub(crate) fn validate_id(id: &str) -> Result<(), String> {
+    Uuid::parse_str(id)
+        .map(|_| ())
+        .map_err(|_| format!("Invalid ID {}", id))
 }
 
 // Helper functions for conversion between Axum types and storable types
 
 // Convert Request to StoredRequest
-pub fn request_to_stored(request: &Request<Bytes>) -> Result<StoredRequest, String> {
+pub fn request_to_stored(request: &Request<Bytes>, header_deny_list: &[String]) -> Result<StoredRequest, String> {
     // Get method and URI
     let method = request.method().to_string();
     let uri = request.uri().to_string();
 
     // Convert headers
-    let mut headers = HashMap::new();
-    for (name, value) in request.headers() {
-        let name = name.to_string();
-        let value = value.to_str()
-            .map_err(|_| "Failed to convert header value".to_string())?
-            .to_string();
-
-        headers.entry(name)
-            .or_insert_with(Vec::new)
-            .push(value);
-    }
+    let headers = StoredHeaders::from_header_map(request.headers(), header_deny_list);
 
     // Get body bytes
     let body = request.body().to_vec();
@@ -65,66 +339,122 @@ pub fn request_to_stored(request: &Request<Bytes>) -> Result<StoredRequest, Stri
 }
 
 // Convert Response to StoredResponse
-pub fn response_to_stored(response: &Response<Bytes>) -> Result<StoredResponse, String> {
+pub fn response_to_stored(response: &Response<Bytes>, header_deny_list: &[String]) -> Result<StoredResponse, String> {
     // Get status
     let status = response.status().as_u16();
 
     // Convert headers
-    let mut headers = HashMap::new();
-    for (name, value) in response.headers() {
-        let name = name.to_string();
-        let value = value.to_str()
-            .map_err(|_| "Failed to convert header value".to_string())?
-            .to_string();
-
-        headers.entry(name)
-            .or_insert_with(Vec::new)
-            .push(value);
-    }
+    let headers = StoredHeaders::from_header_map(response.headers(), header_deny_list);
 
     // Get body bytes
     let body = response.body().to_vec();
 
+    // Trailers only exist once the body was read, they come as an extension
+    let trailers = response.extensions()
+        .get::<RecordedTrailers>()
+        .map(|recorded| StoredHeaders::from_header_map(&recorded.0, header_deny_list))
+        .unwrap_or_default();
+
+    // Streamed responses carry their events as an extension
+    let events = response.extensions()
+        .get::<RecordedEvents>()
+        .map(|recorded| recorded.0.clone())
+        .unwrap_or_default();
+
+    // Upgraded responses carry their frames the same way
+    let frames = response.extensions()
+        .get::<RecordedFrames>()
+        .map(|recorded| recorded.0.clone())
+        .unwrap_or_default();
+
     Ok(StoredResponse {
         status,
         headers,
         body,
+        body_file: response.extensions().get::<RecordedBodyFile>().map(|recorded| recorded.0.clone()),
+        trailers,
+        events,
+        frames,
+        latency: response.extensions().get::<StoredLatency>().copied(),
     })
 }
 
 // Convert StoredRequest to Request
 pub fn stored_to_request(stored: &StoredRequest) -> Result<Request<Bytes>, String> {
     // Create request builder
-    let mut builder = Request::builder()
+    let mut request = Request::builder()
         .method(stored.method.as_str())
-        .uri(stored.uri.as_str());
+        .uri(stored.uri.as_str())
+        .body(Bytes::from(stored.body.clone()))
+        .map_err(|e| format!("Failed to build request: {}", e))?;
 
     // Add headers
-    for (name, values) in &stored.headers {
-        for value in values {
-            builder = builder.header(name, value);
-        }
-    }
+    *request.headers_mut() = stored.headers.to_header_map()?;
 
-    // Build request with body
-    builder.body(Bytes::from(stored.body.clone()))
-        .map_err(|e| format!("Failed to build request: {}", e))
+    Ok(request)
 }
 
 // Convert StoredResponse to Response
 pub fn stored_to_response(stored: &StoredResponse) -> Result<Response<Bytes>, String> {
     // Create response builder
-    let mut builder = Response::builder()
-        .status(stored.status);
+    let mut response = Response::builder()
+        .status(stored.status)
+        .body(Bytes::from(stored.body.clone()))
+        .map_err(|e| format!("Failed to build response: {}", e))?;
 
     // Add headers
-    for (name, values) in &stored.headers {
-        for value in values {
-            builder = builder.header(name, value);
-        }
+    *response.headers_mut() = stored.headers.to_header_map()?;
+
+    if !stored.trailers.is_empty() {
+        response.extensions_mut().insert(RecordedTrailers(stored.trailers.to_header_map()?));
+    }
+
+    Ok(response)
+}
+
+// Read a stub's body file, which has to be inside the body files directory
+fn read_body_file(body_files: &Path, path: &str) -> Result<Vec<u8>, String> {
+    let base = body_files.canonicalize()
+        .map_err(|e| format!("Failed to open body files directory {}: {}", body_files.display(), e))?;
+
+    // Links and .. are resolved before the file is checked
+    let file = base.join(path).canonicalize()
+        .map_err(|e| format!("Failed to read body file {}: {}", path, e))?;
+    if !file.starts_with(&base) {
+        return Err(format!("Body file {} is outside {}", path, body_files.display()));
     }
 
-    // Build response with body
-    builder.body(Bytes::from(stored.body.clone()))
-        .map_err(|e| format!("Failed to build response: {}", e))
-}
\ No newline at end of file
+    std::fs::read(&file).map_err(|e| format!("Failed to read body file {}: {}", path, e))
+}
+
+// Build the response a stub answers with, body files are read from body_files
+pub fn stub_to_response(stub: &StubResponse, body_files: &Path) -> Result<Response<Bytes>, String> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &stub.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| format!("Invalid header value for {}: {}", name, e))?;
+        headers.append(name, value);
+    }
+
+    let body = match (&stub.body, &stub.body_file) {
+        (_, Some(path)) => read_body_file(body_files, path)?,
+        (Some(serde_json::Value::String(text)), None) => text.clone().into_bytes(),
+        (Some(value), None) => {
+            if !headers.contains_key(CONTENT_TYPE) {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            }
+            value.to_string().into_bytes()
+        },
+        (None, None) => Vec::new(),
+    };
+
+    let mut response = Response::builder()
+        .status(stub.status)
+        .body(Bytes::from(body))
+        .map_err(|e| format!("Failed to build response: {}", e))?;
+    *response.headers_mut() = headers;
+
+    Ok(response)
+}
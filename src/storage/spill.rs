@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Body copied while it streams past, kept in memory up to a threshold and
+// spilled to a temporary file beyond it. Once the stream ends a spilled body
+// is copied into the storage as a file, or read back whole for storages that
+// keep bodies inline
+pub struct SpillBuffer {
+    memory: Vec<u8>,
+    file: Option<File>,
+    threshold: usize,
+    len: usize,
+}
+
+impl SpillBuffer {
+    // Create an empty buffer that spills past the given number of bytes
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            memory: Vec::new(),
+            file: None,
+            threshold,
+            len: 0,
+        }
+    }
+
+    // Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Whether nothing was written yet
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Whether the body moved to a temporary file
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    // Append a chunk of the body
+    pub fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.len += data.len();
+
+        if self.file.is_none() && self.len > self.threshold {
+            // The file is removed by the OS as soon as it is closed
+            let mut file = tempfile::tempfile()
+                .map_err(|e| format!("Failed to create spill file: {}", e))?;
+            file.write_all(&self.memory)
+                .map_err(|e| format!("Failed to write spill file: {}", e))?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+
+        match &mut self.file {
+            Some(file) => file.write_all(data)
+                .map_err(|e| format!("Failed to write spill file: {}", e)),
+            None => {
+                self.memory.extend_from_slice(data);
+                Ok(())
+            },
+        }
+    }
+
+    // The temporary file of a spilled body, rewound to its start
+    pub fn spill_file(&mut self) -> Result<Option<&mut File>, String> {
+        match &mut self.file {
+            Some(file) => {
+                file.seek(SeekFrom::Start(0))
+                    .map_err(|e| format!("Failed to read spill file: {}", e))?;
+                Ok(Some(file))
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Read the whole body back into memory
+    pub fn into_bytes(self) -> Result<Bytes, String> {
+        match self.file {
+            Some(mut file) => {
+                let mut body = Vec::with_capacity(self.len);
+                file.seek(SeekFrom::Start(0))
+                    .and_then(|_| file.read_to_end(&mut body))
+                    .map_err(|e| format!("Failed to read spill file: {}", e))?;
+                Ok(Bytes::from(body))
+            },
+            None => Ok(Bytes::from(self.memory)),
+        }
+    }
+}
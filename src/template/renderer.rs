@@ -0,0 +1,200 @@
+use axum::{
+    body::Bytes,
+    extract::Request,
+    http::{HeaderValue, header::CONTENT_LENGTH},
+    response::Response,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
+};
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Renders stored response bodies and headers as Handlebars templates that
+// can reference the live request, e.g. {{request.body.orderId}}
+pub struct ResponseTemplater {
+    registry: Handlebars<'static>,
+}
+
+// Default implementation for ResponseTemplater
+impl Default for ResponseTemplater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseTemplater {
+    // Create a templater with the built-in helpers registered
+    pub fn new() -> Self {
+        let mut registry = Handlebars::new();
+
+        // Responses are not HTML, values are inserted as they are
+        registry.register_escape_fn(handlebars::no_escape);
+
+        registry.register_helper("now", Box::new(now_helper));
+        registry.register_helper("uuid", Box::new(uuid_helper));
+        registry.register_helper("randomInt", Box::new(random_int_helper));
+        registry.register_helper("base64", Box::new(base64_helper));
+
+        Self { registry }
+    }
+
+    // Render the body and text headers of a response against a request
+    pub fn render_response(
+        &self,
+        response: Response<Bytes>,
+        req: &Request<Bytes>,
+    ) -> Result<Response<Bytes>, String> {
+        let context = request_context(req);
+        let (mut parts, body) = response.into_parts();
+
+        for value in parts.headers.values_mut() {
+            if let Ok(text) = value.to_str() {
+                if text.contains("{{") {
+                    let rendered = self.render(text, &context)?;
+                    *value = HeaderValue::from_str(&rendered)
+                        .map_err(|e| format!("Template produced an invalid header value: {}", e))?;
+                }
+            }
+        }
+
+        let body = match std::str::from_utf8(&body) {
+            Ok(text) if text.contains("{{") => {
+                // The body length may have changed
+                parts.headers.remove(CONTENT_LENGTH);
+                Bytes::from(self.render(text, &context)?)
+            },
+            _ => body,
+        };
+
+        Ok(Response::from_parts(parts, body))
+    }
+
+    // Render a single template
+    fn render(&self, template: &str, context: &Value) -> Result<String, String> {
+        self.registry.render_template(template, context)
+            .map_err(|e| format!("Failed to render response template: {}", e))
+    }
+}
+
+// Build the data templates can reference
+fn request_context(req: &Request<Bytes>) -> Value {
+    let path: Vec<Value> = req.uri().path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| Value::String(segment.to_string()))
+        .collect();
+
+    // Repeated query parameters and headers expose their first value
+    let mut query = Map::new();
+    for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+        query.entry(key.into_owned()).or_insert(Value::String(value.into_owned()));
+    }
+
+    let mut headers = Map::new();
+    for (name, value) in req.headers() {
+        headers.entry(name.as_str().to_string())
+            .or_insert(Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned()));
+    }
+
+    // JSON bodies can be navigated, anything else is available as text
+    let body = serde_json::from_slice::<Value>(req.body())
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(req.body()).into_owned()));
+
+    json!({
+        "request": {
+            "method": req.method().as_str(),
+            "url": req.uri().to_string(),
+            "path": path,
+            "query": query,
+            "headers": headers,
+            "body": body,
+        }
+    })
+}
+
+// {{now}} as RFC 3339, {{now "epoch"}} in seconds or {{now "epochMillis"}}
+fn now_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let now = SystemTime::now();
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let rendered = match h.param(0).and_then(|p| p.value().as_str()) {
+        Some("epoch") => since_epoch.as_secs().to_string(),
+        Some("epochMillis") => since_epoch.as_millis().to_string(),
+        _ => humantime::format_rfc3339_millis(now).to_string(),
+    };
+
+    out.write(&rendered)?;
+    Ok(())
+}
+
+// {{uuid}} as a random version 4 UUID
+fn uuid_helper(
+    _: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&Uuid::new_v4().to_string())?;
+    Ok(())
+}
+
+// {{randomInt}} or {{randomInt 1 6}}, both bounds inclusive
+fn random_int_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let lower = h.param(0).and_then(|p| p.value().as_i64()).unwrap_or(0);
+    let upper = h.param(1).and_then(|p| p.value().as_i64()).unwrap_or(i32::MAX as i64);
+
+    if lower > upper {
+        return Err(RenderErrorReason::Other(
+            format!("randomInt lower bound {} is above upper bound {}", lower, upper)
+        ).into());
+    }
+
+    out.write(&rand::thread_rng().gen_range(lower..=upper).to_string())?;
+    Ok(())
+}
+
+// {{base64 value}} encodes, {{base64 value decode=true}} decodes
+fn base64_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = match h.param(0).map(|p| p.value()) {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+
+    let decode = h.hash_get("decode").and_then(|p| p.value().as_bool()).unwrap_or(false);
+
+    let rendered = if decode {
+        let bytes = BASE64.decode(value.trim())
+            .map_err(|e| RenderErrorReason::Other(format!("Invalid base64 value: {}", e)))?;
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else {
+        BASE64.encode(value.as_bytes())
+    };
+
+    out.write(&rendered)?;
+    Ok(())
+}
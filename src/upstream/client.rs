@@ -0,0 +1,118 @@
+use crate::config::ProxyConfig;
+use crate::upstream::build_tls_config;
+use axum::body::Body;
+use axum::http::Version;
+use hyper::body::Incoming;
+use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use log::debug;
+use std::time::Duration;
+
+// Connector used for every upstream request, speaks both HTTP and HTTPS
+pub type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector>;
+
+// TCP connector shared by the HTTP/1 and HTTP/2 connectors
+fn http_connector(config: &ProxyConfig) -> HttpConnector {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false); // The TLS layer handles https:// targets
+    http.set_connect_timeout(Some(Duration::from_millis(config.connect_timeout_ms)));
+    http
+}
+
+// Build the connector used to reach upstream targets
+pub fn build_connector(config: &ProxyConfig) -> Result<HttpsConnector, String> {
+    let tls_config = build_tls_config(&config.tls)?;
+    let http = http_connector(config);
+
+    let builder = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1();
+
+    // HTTP/2 is only used when the upstream offers it through ALPN
+    let connector = if config.http2 {
+        builder.enable_http2().wrap_connector(http)
+    } else {
+        builder.wrap_connector(http)
+    };
+
+    Ok(connector)
+}
+
+// Build the connector for requests that must go upstream over HTTP/2, either
+// negotiated through ALPN or, for http:// targets, with prior knowledge (h2c)
+pub fn build_http2_connector(config: &ProxyConfig) -> Result<HttpsConnector, String> {
+    let tls_config = build_tls_config(&config.tls)?;
+
+    Ok(HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http2()
+        .wrap_connector(http_connector(config)))
+}
+
+// Long-lived client with a connection pool shared by all sessions
+pub struct UpstreamClient {
+    client: Client<HttpsConnector, Body>,
+    // Only speaks HTTP/2, for requests that arrived over HTTP/2 such as gRPC
+    http2_client: Client<HttpsConnector, Body>,
+    read_timeout: Duration,
+    forward_host_header: bool,
+}
+
+impl UpstreamClient {
+    // Create a new upstream client from the proxy configuration
+    pub fn new(config: &ProxyConfig) -> Result<Self, String> {
+        let connector = build_connector(config)?;
+
+        let client = Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+            .build(connector);
+
+        let http2_client = Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+            .http2_only(true)
+            .build(build_http2_connector(config)?);
+
+        Ok(Self {
+            client,
+            http2_client,
+            read_timeout: Duration::from_millis(config.read_timeout_ms),
+            forward_host_header: config.forward_host_header,
+        })
+    }
+
+    // Time to wait for the response head and for each body frame
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    // Whether requests keep the Host header the client sent
+    pub fn forward_host_header(&self) -> bool {
+        self.forward_host_header
+    }
+
+    // Send a request upstream, reusing a pooled connection when possible.
+    // HTTP/2 requests are sent over HTTP/2 whatever the upstream offers
+    pub async fn send(
+        &self,
+        req: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Incoming>, String> {
+        debug!("Sending upstream request to: {}", req.uri());
+
+        let client = if req.version() == Version::HTTP_2 { &self.http2_client } else { &self.client };
+
+        match tokio::time::timeout(self.read_timeout, client.request(req)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(format!("Failed to send request: {}", e)),
+            Err(_) => Err(format!(
+                "Timed out after {}ms waiting for the upstream response",
+                self.read_timeout.as_millis()
+            )),
+        }
+    }
+}
@@ -0,0 +1,5 @@
+mod client;
+mod tls;
+
+pub use client::{build_connector, build_http2_connector, HttpsConnector, UpstreamClient};
+pub use tls::build_tls_config;
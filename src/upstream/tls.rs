@@ -0,0 +1,111 @@
+use crate::config::{TlsConfig, TlsRootStore};
+use log::{debug, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+// Build the rustls client configuration from the TLS settings
+pub fn build_tls_config(config: &TlsConfig) -> Result<ClientConfig, String> {
+    let provider = Arc::new(ring::default_provider());
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to configure TLS protocol versions: {}", e))?;
+
+    if config.insecure_skip_verify {
+        warn!("Upstream TLS certificate verification is disabled");
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+            .with_no_client_auth());
+    }
+
+    let roots = load_root_store(config)?;
+
+    Ok(builder
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+// Load trusted root certificates for the configured store
+fn load_root_store(config: &TlsConfig) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+
+    match config.root_store {
+        TlsRootStore::System => {
+            let result = rustls_native_certs::load_native_certs();
+            for err in &result.errors {
+                warn!("Failed to load a system certificate: {}", err);
+            }
+
+            let (added, ignored) = roots.add_parsable_certificates(result.certs);
+            debug!("Loaded {} system root certificates ({} ignored)", added, ignored);
+        },
+        TlsRootStore::Webpki => {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        },
+        TlsRootStore::Custom => {
+            let path = config.ca_bundle_path.as_ref()
+                .ok_or_else(|| "The custom root store requires ca_bundle_path".to_string())?;
+
+            let file = File::open(path)
+                .map_err(|e| format!("Failed to open CA bundle {}: {}", path, e))?;
+
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Failed to parse CA bundle {}: {}", path, e))?;
+
+            let (added, _) = roots.add_parsable_certificates(certs);
+            debug!("Loaded {} root certificates from {}", added, path);
+        },
+    }
+
+    if roots.is_empty() {
+        return Err("No trusted root certificates could be loaded".to_string());
+    }
+
+    Ok(roots)
+}
+
+// Certificate verifier that accepts any server certificate
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,50 @@
+// Helpers shared by the integration tests, not every test binary uses all of them
+#![allow(dead_code)]
+
+use api_simulator::config::{AppConfig, ProxyConfig};
+use api_simulator::core::ApiSimulator;
+use axum::{extract::Request, routing::any, Router};
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+
+// Serve an upstream app on a free port, handlers can read the client address
+pub async fn spawn_upstream(app: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    addr
+}
+
+// Upstream answering every request with the URI it saw
+pub async fn echo_upstream() -> SocketAddr {
+    spawn_upstream(Router::new().fallback(any(|req: Request| async move {
+        format!("upstream saw {}", req.uri())
+    }))).await
+}
+
+// Start a simulator on a free port, it accepts connections once this returns
+pub async fn spawn_app(config: AppConfig) -> (SocketAddr, JoinHandle<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let simulator = ApiSimulator::new(config).await.unwrap();
+
+    let handle = tokio::spawn(async move {
+        simulator.serve(listener).await.unwrap();
+    });
+
+    (addr, handle)
+}
+
+// Configuration forwarding to the given upstream in the default mode
+pub fn upstream_config(upstream: SocketAddr) -> AppConfig {
+    AppConfig {
+        proxy: ProxyConfig {
+            default_target: format!("http://{}", upstream),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
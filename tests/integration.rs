@@ -2,6 +2,9 @@ use api_simulator::config::{AppConfig, ServerConfig, ProxyConfig};
 use api_simulator::core::ApiSimulator;
 use api_simulator::session::SessionMode;
 
+mod common;
+
+use common::{echo_upstream, spawn_app, spawn_upstream, upstream_config};
 use reqwest::Client;
 use tokio;
 use std::time::Duration;
@@ -25,6 +28,7 @@ async fn test_proxy_functionality() -> Result<(), Box<dyn std::error::Error>> {
             default_mode: SessionMode::Proxy,
             default_target: "".to_string(),
             forward_host_header: true,
+            ..Default::default()
         },
         ..Default::default()
     };
@@ -80,4 +84,1545 @@ async fn test_proxy_functionality() -> Result<(), Box<dyn std::error::Error>> {
     println!("Test completed successfully!");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_passthrough_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Passthrough,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let response = Client::new()
+        .get(format!("http://{}/users?page=2", addr))
+        .header("X-Proxy-Target", format!("http://{}", upstream))
+        .send()
+        .await?;
+
+    assert!(response.status().is_success());
+    assert_eq!(response.text().await?, "upstream saw /users?page=2");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upstream_connections_are_pooled() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    // Upstream noting the client port of every request, one per connection
+    let ports = Arc::new(Mutex::new(HashSet::new()));
+    let seen = ports.clone();
+    let app = Router::new().route("/users", get(move |ConnectInfo(peer): ConnectInfo<SocketAddr>| {
+        let seen = seen.clone();
+        async move {
+            seen.lock().unwrap().insert(peer.port());
+            "users"
+        }
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Passthrough,
+            default_target: format!("http://{}", upstream),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    for _ in 0..5 {
+        let response = client.get(format!("http://{}/users", addr)).send().await?;
+        assert_eq!(response.text().await?, "users");
+    }
+
+    // Every request after the first reuses the pooled connection
+    assert_eq!(ports.lock().unwrap().len(), 1);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upstream_read_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{routing::get, Router};
+
+    let app = Router::new().route("/slow", get(|| async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        "slow"
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Passthrough,
+            default_target: format!("http://{}", upstream),
+            read_timeout_ms: 100,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let response = Client::new().get(format!("http://{}/slow", addr)).send().await?;
+    assert_eq!(response.status(), 500);
+    assert!(response.text().await?.contains("Timed out after 100ms"));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_switch_session_mode_at_runtime() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let config = AppConfig {
+        ..Default::default()
+    };
+
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    let response = client.post(format!("{}/__api_simulator/sessions", base))
+        .json(&serde_json::json!({
+            "session_id": "ci",
+            "mode": "Record",
+            "target": format!("http://{}", upstream),
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+
+    let response = client.get(format!("{}/users", base))
+        .header("X-Session-Id", "ci")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "upstream saw /users");
+
+    let response = client.patch(format!("{}/__api_simulator/sessions/ci", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    let config: serde_json::Value = client.get(format!("{}/__api_simulator/sessions/ci", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(config["mode"], "Replay");
+    assert_eq!(config["target"], format!("http://{}", upstream));
+
+    let response = client.get(format!("{}/users", base))
+        .header("X-Session-Id", "ci")
+        .send()
+        .await?;
+    assert_eq!(response.text().await?, "upstream saw /users");
+
+    let response = client.get(format!("{}/__api_simulator/sessions/missing", base))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_on_miss_mode() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{extract::Request, routing::any, Router};
+    use std::sync::atomic::AtomicUsize;
+
+    // Upstream that counts how many requests reach it
+    let hits = Arc::new(AtomicUsize::new(0));
+    let upstream_hits = hits.clone();
+    let app = Router::new().fallback(any(move |req: Request| {
+        let hits = upstream_hits.clone();
+        async move {
+            hits.fetch_add(1, Ordering::SeqCst);
+            format!("upstream saw {}", req.uri())
+        }
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::RecordOnMiss,
+            default_target: format!("http://{}", upstream),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+
+    for path in ["/users", "/users", "/orders", "/users"] {
+        let response = client.get(format!("http://{}{}", addr, path))
+            .send()
+            .await?;
+        assert_eq!(response.text().await?, format!("upstream saw {}", path));
+    }
+
+    // Only the first request for each path reaches the upstream
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sequential_replay() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{routing::get, Router};
+    use std::sync::atomic::AtomicUsize;
+
+    // Upstream job that finishes on the third poll
+    let polls = Arc::new(AtomicUsize::new(0));
+    let app = Router::new().route("/jobs/42", get(move || {
+        let polls = polls.clone();
+        async move {
+            if polls.fetch_add(1, Ordering::SeqCst) < 2 { "pending" } else { "done" }
+        }
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    let poll = || async {
+        client.get(format!("{}/jobs/42", base)).send().await.unwrap().text().await.unwrap()
+    };
+
+    for expected in ["pending", "pending", "done"] {
+        assert_eq!(poll().await, expected);
+    }
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    for expected in ["pending", "pending", "done", "done"] {
+        assert_eq!(poll().await, expected);
+    }
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "replay": { "on_exhausted": "Cycle" } }))
+        .send()
+        .await?;
+
+    for expected in ["pending", "pending", "done", "pending"] {
+        assert_eq!(poll().await, expected);
+    }
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sequences_only_repeat_the_same_request() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.get(format!("{}/items?page=1", base)).send().await?;
+    client.get(format!("{}/items?page=2", base)).send().await?;
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    // Both recordings tie for an unknown page, they are not one sequence
+    for _ in 0..2 {
+        let response = client.get(format!("{}/items?page=3", base)).send().await?;
+        assert_eq!(response.text().await?, "upstream saw /items?page=1");
+    }
+
+    let response = client.get(format!("{}/items?page=2", base)).send().await?;
+    assert_eq!(response.text().await?, "upstream saw /items?page=2");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_replay_diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.get(format!("{}/users?page=1", base)).send().await?;
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({
+            "mode": "Replay",
+            "matching": { "query": "Exact" },
+            "replay": { "strict": true, "no_match_status": 599 },
+        }))
+        .send()
+        .await?;
+
+    let response = client.get(format!("{}/users?page=1", base)).send().await?;
+    assert_eq!(response.headers()["x-translucent-match"], "hit");
+
+    let response = client.get(format!("{}/users?page=2", base)).send().await?;
+    assert_eq!(response.status().as_u16(), 599);
+    assert_eq!(response.headers()["x-translucent-match"], "miss");
+
+    let body: serde_json::Value = response.json().await?;
+    let failures = &body["candidates"][0]["failures"];
+    assert_eq!(failures.as_array().unwrap().len(), 1);
+    assert_eq!(failures[0]["criterion"], "query");
+    assert_eq!(failures[0]["expected"], "page=1");
+    assert_eq!(failures[0]["actual"], "page=2");
+
+    let misses: serde_json::Value = client.get(format!("{}/__api_simulator/sessions/default/misses", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(misses.as_array().unwrap().len(), 1);
+    assert_eq!(misses[0]["uri"], "/users?page=2");
+    assert_eq!(misses[0]["reason"], "miss");
+
+    client.delete(format!("{}/__api_simulator/sessions/default/misses", base)).send().await?;
+
+    let misses: serde_json::Value = client.get(format!("{}/__api_simulator/sessions/default/misses", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(misses.as_array().unwrap().is_empty());
+
+    let response = client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "replay": { "no_match_status": 42 } }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_capture_correlates_rewritten_ids() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{routing::{get, post}, Router};
+
+    // Upstream that hands out an ID and then serves the resource under it
+    let app = Router::new()
+        .route("/users", post(|| async { r#"{"id":"u-1"}"# }))
+        .route("/users/u-1", get(|| async { "alice" }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.post(format!("{}/users", base)).send().await?;
+    client.get(format!("{}/users/u-1", base)).send().await?;
+
+    // Replayed IDs are regenerated, the capture learns which one the client got
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({
+            "mode": "Replay",
+            "dynamic_values": [{ "pattern": "u-\\d+", "generator": "consistent_random" }],
+            "captures": [{ "name": "user", "json_path": "$.id" }],
+        }))
+        .send()
+        .await?;
+
+    let created: serde_json::Value = client.post(format!("{}/users", base)).send().await?.json().await?;
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_ne!(id, "u-1");
+
+    let resp = client.get(format!("{}/users/{}", base, id)).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await?, "alice");
+
+    // Rules that cannot locate a value are rejected
+    let resp = client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "captures": [{ "name": "broken" }] }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+
+    // So are patterns that do not compile, when the configuration is loaded
+    let resp = client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "captures": [{ "name": "broken", "regex": "u-(" }] }))
+        .send()
+        .await?;
+    assert!(resp.status().is_client_error());
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_captured_values_replaced_as_whole_tokens() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{routing::{get, post}, Router};
+
+    let app = Router::new()
+        .route("/users", post(|| async { "created" }))
+        .route("/users/al", get(|| async { r#"{"name":"al","role":"royal","team":"al-2"}"# }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.post(format!("{}/users", base)).body(r#"{"name":"al"}"#).send().await?;
+    client.get(format!("{}/users/al", base)).send().await?;
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({
+            "mode": "Replay",
+            "captures": [{ "name": "user", "source": "Request", "json_path": "$.name" }],
+        }))
+        .send()
+        .await?;
+
+    // The client names its user bo, which replaces al wherever al is a token
+    client.post(format!("{}/users", base)).body(r#"{"name":"bo"}"#).send().await?;
+
+    let resp = client.get(format!("{}/users/bo", base)).send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    assert_eq!(resp.text().await?, r#"{"name":"bo","role":"royal","team":"bo-2"}"#);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_generated_values_are_not_correlated() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.get(format!("{}/orders/v7", base)).send().await?;
+    client.get(format!("{}/users/1", base)).send().await?;
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({
+            "mode": "Replay",
+            "dynamic_values": [{ "pattern": "v\\d+", "generator": "increment" }],
+        }))
+        .send()
+        .await?;
+
+    // The counter replaces v7 with 1 in the replayed body
+    let resp = client.get(format!("{}/orders/v7", base)).send().await?;
+    assert_eq!(resp.text().await?, "upstream saw /orders/1");
+
+    // A 1 the client sends is its own, not the counter's
+    let resp = client.get(format!("{}/users/1", base)).send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    assert_eq!(resp.text().await?, "upstream saw /users/1");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stubs_alongside_recordings() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.get(format!("{}/orders/1", base)).send().await?;
+
+    // A hand-written error case preferred over anything recorded
+    let resp = client.post(format!("{}/__api_simulator/sessions/default/stubs", base))
+        .json(&serde_json::json!({
+            "priority": 10,
+            "request": { "method": "POST", "path": "/payments", "body": { "amount": 0 } },
+            "response": { "status": 402, "body": { "error": "declined" } },
+        }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // A fallback that only answers what was never recorded
+    client.post(format!("{}/__api_simulator/sessions/default/stubs", base))
+        .json(&serde_json::json!({
+            "priority": 0,
+            "request": { "method": "GET", "path_regex": "/orders/\\d+" },
+            "response": { "body": "unknown order" },
+        }))
+        .send()
+        .await?;
+
+    // Patterns are compiled when the stub is added, a broken one is refused then
+    let resp = client.post(format!("{}/__api_simulator/sessions/default/stubs", base))
+        .json(&serde_json::json!({
+            "request": { "path_regex": "/orders/(" },
+            "response": { "body": "never" },
+        }))
+        .send()
+        .await?;
+    assert!(resp.status().is_client_error());
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    let resp = client.get(format!("{}/orders/1", base)).send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    assert_eq!(resp.text().await?, "upstream saw /orders/1");
+
+    let resp = client.get(format!("{}/orders/2", base)).send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "stub");
+    assert_eq!(resp.text().await?, "unknown order");
+
+    let resp = client.post(format!("{}/payments", base))
+        .json(&serde_json::json!({ "amount": 0, "currency": "EUR" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 402);
+    let body: serde_json::Value = resp.json().await?;
+    assert_eq!(body["error"], "declined");
+
+    let resp = client.post(format!("{}/payments", base))
+        .json(&serde_json::json!({ "amount": 5 }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    let stubs: Vec<serde_json::Value> = client.get(format!("{}/__api_simulator/sessions/default/stubs", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(stubs.len(), 2);
+    assert_eq!(stubs[0]["priority"], 10);
+    assert_eq!(stubs[1]["request"]["path_regex"], "/orders/\\d+");
+
+    let resp = client.delete(format!(
+        "{}/__api_simulator/sessions/default/stubs/{}", base, stubs[1]["id"].as_str().unwrap(),
+    )).send().await?;
+    assert_eq!(resp.status(), 204);
+
+    let resp = client.get(format!("{}/orders/2", base)).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stub_body_files_stay_in_their_directory() -> Result<(), Box<dyn std::error::Error>> {
+    use api_simulator::config::StorageConfig;
+
+    let body_files = tempfile::tempdir()?;
+    let elsewhere = tempfile::tempdir()?;
+    std::fs::write(body_files.path().join("hello.txt"), "hello")?;
+    std::fs::write(elsewhere.path().join("secret.txt"), "secret")?;
+
+    let config = AppConfig {
+        storage: StorageConfig {
+            body_files_path: body_files.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        },
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Replay,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let stubs_url = format!("http://{}/__api_simulator/sessions/default/stubs", addr);
+    let create = |path: &'static str, body_file: String| {
+        client.post(&stubs_url)
+            .json(&serde_json::json!({
+                "request": { "path": path },
+                "response": { "body_file": body_file },
+            }))
+            .send()
+    };
+
+    assert_eq!(create("/hello", "hello.txt".to_string()).await?.status(), 201);
+    let resp = client.get(format!("http://{}/hello", addr)).send().await?;
+    assert_eq!(resp.text().await?, "hello");
+
+    // Paths leaving the directory are refused when the stub is created
+    let secret = elsewhere.path().join("secret.txt");
+    assert_eq!(create("/up", "../secret.txt".to_string()).await?.status(), 400);
+    assert_eq!(create("/absolute", secret.to_str().unwrap().to_string()).await?.status(), 400);
+
+    // Links leading out of it are refused when the stub is answered
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(&secret, body_files.path().join("link.txt"))?;
+        assert_eq!(create("/link", "link.txt".to_string()).await?.status(), 201);
+        let resp = client.get(format!("http://{}/link", addr)).send().await?;
+        assert_eq!(resp.status(), 500);
+        assert!(!resp.text().await?.contains("secret\n"));
+    }
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_interaction_crud() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let interactions_url = format!("{}/__api_simulator/sessions/default/interactions", base);
+
+    client.get(format!("{}/first", base)).send().await?;
+    client.get(format!("{}/second", base)).send().await?;
+
+    let interactions: Vec<serde_json::Value> = client.get(&interactions_url).send().await?.json().await?;
+    assert_eq!(interactions.len(), 2);
+    assert_eq!(interactions[0]["request"]["uri"], "/first");
+    assert!(interactions[0]["timestamp"].is_u64());
+
+    let first_url = format!("{}/{}", interactions_url, interactions[0]["id"].as_str().unwrap());
+    let second_url = format!("{}/{}", interactions_url, interactions[1]["id"].as_str().unwrap());
+
+    // Edit the recorded response
+    let mut first: serde_json::Value = client.get(&first_url).send().await?.json().await?;
+    first["response"]["body"] = serde_json::json!(b"edited".to_vec());
+    first["response"]["headers"] = serde_json::json!([]);
+    let resp = client.put(&first_url).json(&first).send().await?;
+    assert_eq!(resp.status(), 200);
+    let updated: serde_json::Value = resp.json().await?;
+    assert_eq!(updated["id"], interactions[0]["id"]);
+
+    assert_eq!(client.delete(&second_url).send().await?.status(), 204);
+    assert_eq!(client.get(&second_url).send().await?.status(), 404);
+    assert_eq!(client.delete(&second_url).send().await?.status(), 404);
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    assert_eq!(client.get(format!("{}/first", base)).send().await?.text().await?, "edited");
+    assert_eq!(client.get(format!("{}/second", base)).send().await?.status(), 404);
+
+    let resp = client.get(format!("{}/__api_simulator/sessions/missing/interactions", base)).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_journal_and_count() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_target: format!("http://{}", upstream),
+            ..Default::default()
+        },
+        journal_limit: 3,
+        ..Default::default()
+    };
+
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let requests_url = format!("{}/__api_simulator/sessions/default/requests", base);
+
+    client.post(format!("{}/payments", base)).json(&serde_json::json!({ "amount": 5 })).send().await?;
+
+    client.patch(format!("{}/__api_simulator/sessions/default", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    client.post(format!("{}/payments", base)).json(&serde_json::json!({ "amount": 5 })).send().await?;
+    client.get(format!("{}/unknown", base)).send().await?;
+
+    let journal: Vec<serde_json::Value> = client.get(&requests_url).send().await?.json().await?;
+    let outcomes: Vec<_> = journal.iter().map(|entry| entry["outcome"].as_str().unwrap()).collect();
+    assert_eq!(outcomes, vec!["Recorded", "Matched", "Missed"]);
+
+    let count = |matcher: serde_json::Value| {
+        let client = client.clone();
+        let url = format!("{}/count", requests_url);
+        async move {
+            let body: serde_json::Value = client.post(url).json(&matcher).send().await.unwrap().json().await.unwrap();
+            body["count"].as_u64().unwrap()
+        }
+    };
+
+    assert_eq!(count(serde_json::json!({ "method": "POST", "path": "/payments" })).await, 2);
+    assert_eq!(count(serde_json::json!({ "path": "/payments", "outcome": "Matched" })).await, 1);
+    assert_eq!(count(serde_json::json!({ "body": { "amount": 7 } })).await, 0);
+
+    // The oldest entries make room for new ones
+    client.get(format!("{}/unknown", base)).send().await?;
+    let journal: Vec<serde_json::Value> = client.get(&requests_url).send().await?.json().await?;
+    assert_eq!(journal.len(), 3);
+    assert_eq!(journal[0]["outcome"], "Matched");
+
+    assert_eq!(client.delete(&requests_url).send().await?.status(), 204);
+    assert_eq!(count(serde_json::json!({})).await, 0);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sessions_survive_restart() -> Result<(), Box<dyn std::error::Error>> {
+    use api_simulator::config::StorageConfig;
+
+    let upstream = echo_upstream().await;
+    let dir = tempfile::tempdir()?;
+
+    let config_for = || AppConfig {
+        storage: StorageConfig {
+            type_: "filesystem".to_string(),
+            path: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let config = config_for();
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    client.post(format!("{}/__api_simulator/sessions", base))
+        .json(&serde_json::json!({
+            "session_id": "ci",
+            "mode": "Record",
+            "target": format!("http://{}", upstream),
+        }))
+        .send()
+        .await?;
+    client.get(format!("{}/users", base)).header("X-Session-Id", "ci").send().await?;
+    client.patch(format!("{}/__api_simulator/sessions/ci", base))
+        .json(&serde_json::json!({ "mode": "Replay" }))
+        .send()
+        .await?;
+
+    // Unknown sessions are not created unless configured to
+    let resp = client.get(format!("{}/users", base)).header("X-Session-Id", "other").send().await?;
+    assert_eq!(resp.status(), 404);
+
+    server_handle.abort();
+
+    let config = config_for();
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let base = format!("http://{}", addr);
+
+    let config: serde_json::Value = client.get(format!("{}/__api_simulator/sessions/ci", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(config["mode"], "Replay");
+
+    let resp = client.get(format!("{}/users", base)).header("X-Session-Id", "ci").send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    assert_eq!(resp.text().await?, "upstream saw /users");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clone_session() -> Result<(), Box<dyn std::error::Error>> {
+    let upstream = echo_upstream().await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let sessions_url = format!("{}/__api_simulator/sessions", base);
+
+    // The baseline recording
+    client.get(format!("{}/users", base)).send().await?;
+
+    let resp = client.post(format!("{}/default/clone", sessions_url))
+        .json(&serde_json::json!({ "session_id": "test-1", "mode": "RecordOnMiss" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let config: serde_json::Value = resp.json().await?;
+    assert_eq!(config["mode"], "RecordOnMiss");
+
+    let resp = client.get(format!("{}/users", base)).header("X-Session-Id", "test-1").send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    client.get(format!("{}/orders", base)).header("X-Session-Id", "test-1").send().await?;
+
+    let interactions = |session: &'static str| {
+        let client = client.clone();
+        let url = format!("{}/{}/interactions", sessions_url, session);
+        async move {
+            client.get(url).send().await.unwrap().json::<Vec<serde_json::Value>>().await.unwrap()
+        }
+    };
+    assert_eq!(interactions("default").await.len(), 1);
+    assert_eq!(interactions("test-1").await.len(), 2);
+
+    // Copies keep what was recorded beside the body, such as the upstream latency
+    let baseline = interactions("default").await;
+    assert!(baseline[0]["response"]["latency"].is_object());
+    assert_eq!(interactions("test-1").await[0]["response"]["latency"], baseline[0]["response"]["latency"]);
+
+    let resp = client.post(format!("{}/default/clone", sessions_url))
+        .json(&serde_json::json!({ "session_id": "test-1" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 409);
+
+    let resp = client.post(format!("{}/missing/clone", sessions_url))
+        .json(&serde_json::json!({ "session_id": "test-2" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_extractors() -> Result<(), Box<dyn std::error::Error>> {
+    use api_simulator::config::SessionExtractor;
+    use base64::Engine;
+
+    let upstream = echo_upstream().await;
+
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Passthrough,
+            default_target: format!("http://{}", upstream),
+            ..Default::default()
+        },
+        auto_generate_sessions: true,
+        session_extractors: vec![
+            SessionExtractor::PathPrefix { prefix: "/s".to_string() },
+            SessionExtractor::Cookie { name: "sim".to_string() },
+            SessionExtractor::JwtClaim { claim: "sub".to_string(), header: "Authorization".to_string() },
+            SessionExtractor::Query { name: "session".to_string() },
+        ],
+        ..Default::default()
+    };
+
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    // The session never reaches the upstream
+    let resp = client.get(format!("{}/s/alpha/users?page=1", base)).send().await?;
+    assert_eq!(resp.text().await?, "upstream saw /users?page=1");
+
+    let resp = client.get(format!("{}/orders?session=beta&page=2", base)).send().await?;
+    assert_eq!(resp.text().await?, "upstream saw /orders?page=2");
+
+    // The other parameters reach the upstream exactly as sent
+    let resp = client.get(format!("{}/orders?ids=1,2&session=beta&q=a%20b+c&flag", base)).send().await?;
+    assert_eq!(resp.text().await?, "upstream saw /orders?ids=1,2&q=a%20b+c&flag");
+
+    // An empty path segment names no session and leaves the path alone
+    let resp = client.get(format!("{}/s//users?session=epsilon", base)).send().await?;
+    assert_eq!(resp.text().await?, "upstream saw /s//users");
+
+    client.get(format!("{}/orders", base)).header("Cookie", "theme=dark; sim=gamma").send().await?;
+
+    let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(r#"{"sub":"delta"}"#);
+    client.get(format!("{}/orders", base))
+        .header("Authorization", format!("Bearer eyJhbGciOiJub25lIn0.{}.", claims))
+        .send()
+        .await?;
+
+    let mut sessions: Vec<String> = client.get(format!("{}/__api_simulator/sessions", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    sessions.sort();
+    assert_eq!(sessions, vec!["alpha", "beta", "default", "delta", "epsilon", "gamma"]);
+
+    // IDs that could name a path outside the storage are refused
+    let resp = client.get(format!("{}/orders?session=..", base)).send().await?;
+    assert_eq!(resp.status(), 400);
+    let resp = client.post(format!("{}/__api_simulator/sessions", base))
+        .json(&serde_json::json!({ "session_id": "../outside" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_bodies_stream_and_record() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{body::{Body, Bytes}, routing::{get, post}, Router};
+
+    // Bigger than the 1 MB spill threshold, once with a known length and once chunked
+    let big = "x".repeat(3 * 1024 * 1024);
+    let app = Router::new()
+        .route("/big", get({
+            let big = big.clone();
+            move || async move { big }
+        }))
+        .route("/big-copy", get({
+            let big = big.clone();
+            move || async move { big }
+        }))
+        // Below the spill threshold, above the limit set further down
+        .route("/medium", get(|| async { "m".repeat(2048) }))
+        .route("/chunked", get(|| async {
+            let chunks = (0..4).map(|i| Ok::<_, std::io::Error>(format!("chunk {}\n", i)));
+            Body::from_stream(futures_util::stream::iter(chunks))
+        }))
+        .route("/upload", post(|body: Bytes| async move { format!("received {} bytes", body.len()) }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let session_url = format!("{}/__api_simulator/sessions/default", base);
+
+    assert_eq!(client.get(format!("{}/big", base)).send().await?.text().await?.len(), big.len());
+    let chunked = client.get(format!("{}/chunked", base)).send().await?.text().await?;
+    assert_eq!(chunked, "chunk 0\nchunk 1\nchunk 2\nchunk 3\n");
+
+    // Past the session limit requests are refused and responses are not recorded
+    client.patch(&session_url).json(&serde_json::json!({ "max_body_bytes": 1024 })).send().await?;
+
+    let resp = client.post(format!("{}/upload", base)).body(vec![0u8; 2048]).send().await?;
+    assert_eq!(resp.status(), 413);
+
+    let resp = client.get(format!("{}/big-copy", base)).send().await?;
+    assert_eq!(resp.text().await?.len(), big.len());
+    let resp = client.get(format!("{}/medium", base)).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await?.len(), 2048);
+
+    // Forwarded without recording, request bodies are streamed whatever their size
+    client.patch(&session_url).json(&serde_json::json!({ "mode": "Passthrough" })).send().await?;
+    let resp = client.post(format!("{}/upload", base)).body(vec![0u8; 2048]).send().await?;
+    assert_eq!(resp.text().await?, "received 2048 bytes");
+
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "mode": "Replay", "max_body_bytes": 10485760 }))
+        .send()
+        .await?;
+
+    assert_eq!(client.get(format!("{}/big", base)).send().await?.text().await?, big);
+    assert_eq!(client.get(format!("{}/chunked", base)).send().await?.text().await?, chunked);
+    assert_eq!(client.get(format!("{}/big-copy", base)).send().await?.status(), 404);
+    assert_eq!(client.get(format!("{}/medium", base)).send().await?.status(), 404);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spilled_bodies_are_kept_as_files() -> Result<(), Box<dyn std::error::Error>> {
+    use api_simulator::config::StorageConfig;
+    use axum::{routing::get, Router};
+
+    let report = "r".repeat(4096);
+    let app = Router::new().route("/report", get({
+        let report = report.clone();
+        move || async move { report }
+    }));
+    let upstream = spawn_upstream(app).await;
+    let dir = tempfile::tempdir()?;
+
+    let mut config = upstream_config(upstream);
+    config.storage = StorageConfig {
+        type_: "filesystem".to_string(),
+        path: dir.path().to_str().unwrap().to_string(),
+        spill_threshold_bytes: 1024,
+        ..Default::default()
+    };
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let sessions_url = format!("{}/__api_simulator/sessions", base);
+
+    assert_eq!(client.get(format!("{}/report", base)).send().await?.text().await?, report);
+
+    // The interaction refers to the body instead of holding it
+    let interactions: Vec<serde_json::Value> = client.get(format!("{}/default/interactions", sessions_url))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let response = &interactions[0]["response"];
+    assert_eq!(response["body"], serde_json::json!([]));
+    let body_file = dir.path().join("default/bodies").join(response["body_file"].as_str().unwrap());
+    assert_eq!(std::fs::read(&body_file)?, report.as_bytes());
+
+    // Copies get their own file, deleting the interaction deletes its file
+    client.post(format!("{}/default/clone", sessions_url))
+        .json(&serde_json::json!({ "session_id": "copy", "mode": "Replay" }))
+        .send()
+        .await?;
+    let resp = client.delete(format!(
+        "{}/default/interactions/{}", sessions_url, interactions[0]["id"].as_str().unwrap(),
+    )).send().await?;
+    assert_eq!(resp.status(), 204);
+    assert!(!body_file.exists());
+
+    let resp = client.get(format!("{}/report", base)).header("X-Session-Id", "copy").send().await?;
+    assert_eq!(resp.headers()["x-translucent-match"], "hit");
+    assert_eq!(resp.text().await?, report);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_streams_replay_with_timing() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{body::Body, response::IntoResponse, routing::get, Router};
+    use futures_util::StreamExt;
+    use std::time::Instant;
+
+    // Three events 300 ms apart
+    let app = Router::new().route("/events", get(|| async {
+        let events = futures_util::stream::iter(0..3).then(|i| async move {
+            if i > 0 {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            Ok::<_, std::io::Error>(format!("id: {}\ndata: tick {}\n\n", i, i))
+        });
+        ([("Content-Type", "text/event-stream")], Body::from_stream(events)).into_response()
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let session_url = format!("{}/__api_simulator/sessions/default", base);
+    let expected = "id: 0\ndata: tick 0\n\nid: 1\ndata: tick 1\n\nid: 2\ndata: tick 2\n\n";
+
+    // Events reach the client while the stream is still being recorded
+    let start = Instant::now();
+    let mut resp = client.get(format!("{}/events", base)).send().await?;
+    let first = resp.chunk().await?.unwrap();
+    assert!(first.starts_with(b"id: 0"));
+    assert!(start.elapsed() < Duration::from_millis(250));
+    let mut body = first.to_vec();
+    while let Some(chunk) = resp.chunk().await? {
+        body.extend_from_slice(&chunk);
+    }
+    assert_eq!(String::from_utf8(body)?, expected);
+
+    let interactions: serde_json::Value = client.get(format!("{}/interactions", session_url)).send().await?.json().await?;
+    let events = interactions[0]["response"]["events"].as_array().unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[1]["data"], "id: 1\ndata: tick 1\n\n");
+    assert!(events[2]["offset_ms"].as_u64().unwrap() >= 500);
+
+    // Replay keeps the spacing
+    client.patch(&session_url).json(&serde_json::json!({ "mode": "Replay" })).send().await?;
+
+    let start = Instant::now();
+    let resp = client.get(format!("{}/events", base)).send().await?;
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    assert_eq!(resp.text().await?, expected);
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    // Faster, then all at once
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "event_speed": 10.0 } }))
+        .send()
+        .await?;
+
+    let start = Instant::now();
+    assert_eq!(client.get(format!("{}/events", base)).send().await?.text().await?, expected);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(400));
+
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "event_timing": "Immediate" } }))
+        .send()
+        .await?;
+
+    let start = Instant::now();
+    assert_eq!(client.get(format!("{}/events", base)).send().await?.text().await?, expected);
+    assert!(start.elapsed() < Duration::from_millis(50));
+
+    let resp = client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "event_speed": 0.0 } }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_streams_outlive_the_read_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{body::Body, response::IntoResponse, routing::get, Router};
+    use futures_util::StreamExt;
+
+    // Two events with a pause longer than the read timeout, then one cut short
+    let app = Router::new()
+        .route("/quiet", get(|| async {
+            let events = futures_util::stream::iter(0..2).then(|i| async move {
+                if i > 0 {
+                    tokio::time::sleep(Duration::from_millis(600)).await;
+                }
+                Ok::<_, std::io::Error>(format!("data: tick {}\n\n", i))
+            });
+            ([("Content-Type", "text/event-stream")], Body::from_stream(events)).into_response()
+        }))
+        .route("/broken", get(|| async {
+            let events = futures_util::stream::iter(0..2).then(|i| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                match i {
+                    0 => Ok("data: tick 0\n\n".to_string()),
+                    _ => Err(std::io::Error::other("upstream went away")),
+                }
+            });
+            ([("Content-Type", "text/event-stream")], Body::from_stream(events)).into_response()
+        }));
+    let upstream = spawn_upstream(app).await;
+
+    let mut config = upstream_config(upstream);
+    config.proxy.read_timeout_ms = 300;
+    let (addr, server_handle) = spawn_app(config).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+
+    let resp = client.get(format!("{}/quiet", base)).send().await?;
+    assert_eq!(resp.text().await?, "data: tick 0\n\ndata: tick 1\n\n");
+
+    // The client sees the failure, the events before it are still recorded
+    let resp = client.get(format!("{}/broken", base)).send().await?;
+    assert!(resp.text().await.is_err());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let interactions: Vec<serde_json::Value> = client.get(format!("{}/__api_simulator/sessions/default/interactions", base))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(interactions.len(), 2);
+    assert_eq!(interactions[0]["response"]["events"].as_array().unwrap().len(), 2);
+    assert_eq!(interactions[1]["response"]["events"][0]["data"], "data: tick 0\n\n");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+// Read an HTTP head off a stream byte by byte, leaving any frame after it unread
+async fn read_http_head(stream: &mut tokio::net::TcpStream) -> std::io::Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+// Write a frame with a short payload, clients mask theirs (with a zero key here)
+async fn write_ws_frame(stream: &mut tokio::net::TcpStream, opcode: u8, payload: &[u8], masked: bool) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut frame = vec![0x80 | opcode];
+    if masked {
+        frame.push(0x80 | payload.len() as u8);
+        frame.extend_from_slice(&[0, 0, 0, 0]);
+    } else {
+        frame.push(payload.len() as u8);
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await
+}
+
+// Read an unmasked frame with a short payload
+async fn read_ws_frame(stream: &mut tokio::net::TcpStream) -> std::io::Result<(u8, String)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut payload = vec![0u8; (header[1] & 0x7f) as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((header[0] & 0x0f, String::from_utf8_lossy(&payload).into_owned()))
+}
+
+fn ws_accept(key: &str) -> String {
+    use base64::Engine;
+
+    let mut context = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key.as_bytes());
+    context.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    base64::engine::general_purpose::STANDARD.encode(context.finish())
+}
+
+// Open a WebSocket offering compression, returning the stream and the
+// handshake response
+async fn ws_connect(addr: std::net::SocketAddr, path: &str) -> std::io::Result<(tokio::net::TcpStream, String)> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let handshake = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        path, addr,
+    );
+    stream.write_all(handshake.as_bytes()).await?;
+    let head = read_http_head(&mut stream).await?;
+    Ok((stream, head))
+}
+
+#[tokio::test]
+async fn test_websockets_record_and_replay() -> Result<(), Box<dyn std::error::Error>> {
+    use std::time::Instant;
+
+    // Greets, then answers every "ping N" with "pong N" and echoes closing.
+    // Accepts compression when offered, which would make frames unreadable
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let upstream = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                use tokio::io::AsyncWriteExt;
+
+                let head = read_http_head(&mut stream).await.unwrap();
+                let key = head.lines()
+                    .find_map(|line| line.strip_prefix("sec-websocket-key: ").or_else(|| line.strip_prefix("Sec-WebSocket-Key: ")))
+                    .unwrap();
+                let extensions = if head.to_lowercase().contains("sec-websocket-extensions") {
+                    "Sec-WebSocket-Extensions: permessage-deflate\r\n"
+                } else {
+                    ""
+                };
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+                    ws_accept(key),
+                    extensions,
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                write_ws_frame(&mut stream, 0x1, b"welcome", false).await.unwrap();
+
+                loop {
+                    let (opcode, payload) = {
+                        use tokio::io::AsyncReadExt;
+
+                        let mut header = [0u8; 2];
+                        if stream.read_exact(&mut header).await.is_err() {
+                            return;
+                        }
+                        let mut mask = [0u8; 4];
+                        stream.read_exact(&mut mask).await.unwrap();
+                        let mut payload = vec![0u8; (header[1] & 0x7f) as usize];
+                        stream.read_exact(&mut payload).await.unwrap();
+                        for (i, byte) in payload.iter_mut().enumerate() {
+                            *byte ^= mask[i % 4];
+                        }
+                        (header[0] & 0x0f, String::from_utf8(payload).unwrap())
+                    };
+
+                    if opcode == 0x8 {
+                        write_ws_frame(&mut stream, 0x8, b"", false).await.unwrap();
+                        return;
+                    }
+                    let reply = payload.replace("ping", "pong");
+                    write_ws_frame(&mut stream, 0x1, reply.as_bytes(), false).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let session_url = format!("http://{}/__api_simulator/sessions/default", addr);
+    let accept = "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    // Record a conversation through the simulator
+    let (mut stream, head) = ws_connect(addr, "/prices").await?;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.to_lowercase().contains(&accept.to_lowercase()));
+    assert!(!head.to_lowercase().contains("sec-websocket-extensions"));
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "welcome".to_string()));
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    write_ws_frame(&mut stream, 0x1, b"ping 1", true).await?;
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "pong 1".to_string()));
+    write_ws_frame(&mut stream, 0x8, b"", true).await?;
+    assert_eq!(read_ws_frame(&mut stream).await?.0, 0x8);
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let interactions: serde_json::Value = client.get(format!("{}/interactions", session_url)).send().await?.json().await?;
+    assert_eq!(interactions[0]["response"]["status"], 101);
+    let frames = interactions[0]["response"]["frames"].as_array().unwrap();
+    let directions: Vec<_> = frames.iter().map(|frame| frame["direction"].as_str().unwrap()).collect();
+    assert_eq!(directions, vec!["Server", "Client", "Server", "Client", "Server"]);
+    assert!(frames[1]["offset_ms"].as_u64().unwrap() >= 250);
+
+    // On the timeline the reply comes without asking
+    client.patch(&session_url).json(&serde_json::json!({ "mode": "Replay" })).send().await?;
+
+    let start = Instant::now();
+    let (mut stream, head) = ws_connect(addr, "/prices").await?;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.to_lowercase().contains(&accept.to_lowercase()));
+    assert!(!head.to_lowercase().contains("sec-websocket-extensions"));
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "welcome".to_string()));
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "pong 1".to_string()));
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    write_ws_frame(&mut stream, 0x8, b"", true).await?;
+    assert_eq!(read_ws_frame(&mut stream).await?.0, 0x8);
+
+    // Triggered, the reply waits for the recorded client message
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "frame_replay": "ClientFrames" } }))
+        .send()
+        .await?;
+
+    let (mut stream, _) = ws_connect(addr, "/prices").await?;
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "welcome".to_string()));
+    write_ws_frame(&mut stream, 0x1, b"ping 2", true).await?;
+    assert!(tokio::time::timeout(Duration::from_millis(500), read_ws_frame(&mut stream)).await.is_err());
+    write_ws_frame(&mut stream, 0x1, b"ping 1", true).await?;
+    assert_eq!(read_ws_frame(&mut stream).await?, (0x1, "pong 1".to_string()));
+    write_ws_frame(&mut stream, 0x9, b"hb", true).await?;
+    assert_eq!(read_ws_frame(&mut stream).await?, (0xa, "hb".to_string()));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+// Frame a gRPC message with its uncompressed length prefix
+fn grpc_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+#[tokio::test]
+async fn test_grpc_over_h2c_record_and_replay() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{body::{Body, Bytes}, http::{HeaderMap, Version}, response::IntoResponse, routing::post, Router};
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+    use hyper_util::client::legacy::{connect::HttpConnector, Client as HyperClient};
+    use hyper_util::rt::TokioExecutor;
+
+    // Answers each message with a price, the status comes in the trailers
+    let app = Router::new().route("/pricing.Pricing/GetPrice", post(|version: Version, body: Bytes| async move {
+        let mut reply = b"price of ".to_vec();
+        reply.extend_from_slice(&body[5..]);
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        trailers.insert("grpc-message", "ok".parse().unwrap());
+
+        let frames = futures_util::stream::iter([
+            Ok::<_, std::convert::Infallible>(Frame::data(Bytes::from(grpc_frame(&reply)))),
+            Ok(Frame::trailers(trailers)),
+        ]);
+        (
+            [("content-type", "application/grpc"), ("x-upstream-version", if version == Version::HTTP_2 { "2" } else { "1" })],
+            Body::new(StreamBody::new(frames)),
+        ).into_response()
+    }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    // gRPC clients speak HTTP/2 with prior knowledge
+    let grpc: HyperClient<HttpConnector, Full<Bytes>> = HyperClient::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build_http();
+    let call = |message: &'static [u8]| {
+        let request = hyper::Request::post(format!("http://{}/pricing.Pricing/GetPrice", addr))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Full::new(Bytes::from(grpc_frame(message))))
+            .unwrap();
+        let response = grpc.request(request);
+        async move {
+            let response = response.await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let collected = response.into_body().collect().await?;
+            let trailers = collected.trailers().cloned();
+            Ok::<_, Box<dyn std::error::Error>>((status, headers, collected.to_bytes(), trailers))
+        }
+    };
+
+    let client = Client::new();
+    let session_url = format!("http://{}/__api_simulator/sessions/default", addr);
+
+    // Two calls to the same method, told apart by their message
+    for message in [&b"EURUSD"[..], &b"GBPUSD"[..]] {
+        let (status, headers, body, trailers) = call(message).await?;
+        assert_eq!(status, 200);
+        assert_eq!(headers["x-upstream-version"], "2");
+        assert_eq!(&body[5..], [&b"price of "[..], message].concat());
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+    }
+
+    let interactions: serde_json::Value = client.get(format!("{}/interactions", session_url)).send().await?.json().await?;
+    assert_eq!(interactions[0]["response"]["trailers"], serde_json::json!([["grpc-status", "0"], ["grpc-message", "ok"]]));
+
+    client.patch(&session_url).json(&serde_json::json!({ "mode": "Replay" })).send().await?;
+
+    let (status, headers, body, trailers) = call(b"GBPUSD").await?;
+    assert_eq!(status, 200);
+    assert_eq!(headers["x-translucent-match"], "hit");
+    assert_eq!(&body[..], grpc_frame(b"price of GBPUSD"));
+    let trailers = trailers.unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["grpc-message"], "ok");
+
+    // An unknown message misses, naming the message that differs
+    let (status, _, body, _) = call(b"USDJPY").await?;
+    assert_eq!(status, 404);
+    assert!(String::from_utf8_lossy(&body).contains("message[0]"));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_latency() -> Result<(), Box<dyn std::error::Error>> {
+    use axum::{routing::get, Router};
+    use std::time::Instant;
+
+    let app = Router::new()
+        .route("/slow", get(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            "slow"
+        }))
+        .route("/fast", get(|| async { "fast" }));
+    let upstream = spawn_upstream(app).await;
+
+    let (addr, server_handle) = spawn_app(upstream_config(upstream)).await;
+
+    let client = Client::new();
+    let base = format!("http://{}", addr);
+    let session_url = format!("{}/__api_simulator/sessions/default", base);
+
+    // Time a request, checking what it answered
+    let timed = |path: &'static str| {
+        let client = client.clone();
+        async move {
+            let start = Instant::now();
+            let body = client.get(format!("http://{}{}", addr, path)).send().await?.text().await?;
+            assert_eq!(body, &path[1..]);
+            Ok::<_, reqwest::Error>(start.elapsed())
+        }
+    };
+
+    timed("/slow").await?;
+    timed("/fast").await?;
+
+    let interactions: serde_json::Value = client.get(format!("{}/interactions", session_url)).send().await?.json().await?;
+    let latency = &interactions[0]["response"]["latency"];
+    assert!(latency["ttfb_ms"].as_u64().unwrap() >= 300);
+    assert!(latency["total_ms"].as_u64().unwrap() >= latency["ttfb_ms"].as_u64().unwrap());
+
+    // Replay answers at once unless asked otherwise
+    client.patch(&session_url).json(&serde_json::json!({ "mode": "Replay" })).send().await?;
+    assert!(timed("/slow").await? < Duration::from_millis(200));
+
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "latency": { "delay": { "type": "recorded" } } } }))
+        .send()
+        .await?;
+    assert!(timed("/slow").await? >= Duration::from_millis(300));
+    assert!(timed("/fast").await? < Duration::from_millis(200));
+
+    // A route's delay wins over the session's
+    client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "latency": {
+            "delay": { "type": "uniform", "min_ms": 0, "max_ms": 20 },
+            "routes": [{ "path": "/fast", "delay": { "type": "fixed", "ms": 250 } }],
+        } } }))
+        .send()
+        .await?;
+    assert!(timed("/fast").await? >= Duration::from_millis(250));
+    assert!(timed("/slow").await? < Duration::from_millis(200));
+
+    let resp = client.patch(&session_url)
+        .json(&serde_json::json!({ "replay": { "latency": { "delay": { "type": "uniform", "min_ms": 50, "max_ms": 10 } } } }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+
+    server_handle.abort();
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use api_simulator::matching::{
+    DynamicValueProcessor, DynamicValueRule, MatchConfig, MatchResult, Pattern, QueryMatching, RequestMatcher,
+};
+use std::collections::HashMap;
+use api_simulator::storage::{MemoryStorage, Storage};
+use axum::body::Bytes;
+use axum::extract::Request;
+use axum::response::Response;
+use std::sync::Arc;
+
+fn request(method: &str, uri: &str, body: &str) -> Request<Bytes> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .body(Bytes::from(body.to_string()))
+        .unwrap()
+}
+
+fn record(storage: &Arc<dyn Storage>, req: Request<Bytes>, body: &str) {
+    let response = Response::builder()
+        .status(200)
+        .body(Bytes::from(body.to_string()))
+        .unwrap();
+
+    storage.store_interaction("test", &req, &response).unwrap();
+}
+
+async fn matched_body(
+    storage: &Arc<dyn Storage>,
+    req: Request<Bytes>,
+    config: &MatchConfig,
+) -> Option<String> {
+    matched_body_with(storage, req, config, &DynamicValueProcessor::new()).await
+}
+
+async fn matched_body_with(
+    storage: &Arc<dyn Storage>,
+    req: Request<Bytes>,
+    config: &MatchConfig,
+    dynamic: &DynamicValueProcessor,
+) -> Option<String> {
+    match RequestMatcher::new().match_request(&req, "test", storage, config, dynamic).await.unwrap() {
+        MatchResult::Match(matches) => Some(String::from_utf8(matches[0].response.body.clone()).unwrap()),
+        MatchResult::NoMatch => None,
+    }
+}
+
+#[tokio::test]
+async fn test_query_matching() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    record(&storage, request("GET", "/users?page=1&size=10", ""), "page 1");
+    record(&storage, request("GET", "/users?page=2&size=10", ""), "page 2");
+
+    let ignore = MatchConfig::default();
+    let exact = MatchConfig { query: QueryMatching::Exact, ..Default::default() };
+    let unordered = MatchConfig { query: QueryMatching::Unordered, ..Default::default() };
+
+    assert_eq!(matched_body(&storage, request("GET", "/users?page=2&size=10", ""), &ignore).await.as_deref(), Some("page 2"));
+    assert_eq!(matched_body(&storage, request("GET", "/users?page=3&size=10", ""), &ignore).await.as_deref(), Some("page 1"));
+    assert_eq!(matched_body(&storage, request("GET", "/users?page=2&size=10", ""), &exact).await.as_deref(), Some("page 2"));
+    assert_eq!(matched_body(&storage, request("GET", "/users?size=10&page=2", ""), &exact).await, None);
+    assert_eq!(matched_body(&storage, request("GET", "/users?size=10&page=2", ""), &unordered).await.as_deref(), Some("page 2"));
+}
+
+#[tokio::test]
+async fn test_header_matching() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    record(&storage, request("GET", "/users", ""), "json");
+
+    let config = MatchConfig { headers: vec!["accept".to_string()], ..Default::default() };
+
+    let mut xml = request("GET", "/users", "");
+    xml.headers_mut().insert("accept", "application/xml".parse().unwrap());
+
+    assert_eq!(matched_body(&storage, request("GET", "/users", ""), &config).await.as_deref(), Some("json"));
+    assert_eq!(matched_body(&storage, xml, &config).await, None);
+}
+
+#[tokio::test]
+async fn test_json_body_matching() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    record(&storage, request("POST", "/orders", r#"{"sku":"a","meta":{"nonce":"1"},"items":[{"id":1,"qty":2}]}"#), "order a");
+    record(&storage, request("POST", "/orders", r#"{"sku":"b","meta":{"nonce":"2"},"items":[{"id":2,"qty":1}]}"#), "order b");
+
+    let config = MatchConfig {
+        body: true,
+        ignored_body_paths: vec!["$.meta.nonce".to_string(), "items[*].id".to_string()],
+        ..Default::default()
+    };
+
+    let body = r#"{"items":[{"qty":1,"id":99}],"meta":{"nonce":"fresh"},"sku":"b"}"#;
+    assert_eq!(matched_body(&storage, request("POST", "/orders", body), &config).await.as_deref(), Some("order b"));
+
+    let body = r#"{"sku":"c","meta":{"nonce":"1"},"items":[{"id":1,"qty":2}]}"#;
+    assert_eq!(matched_body(&storage, request("POST", "/orders", body), &config).await, None);
+}
+
+#[tokio::test]
+async fn test_best_match_wins() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    record(&storage, request("POST", "/search", r#"{"q":"shoes","page":1}"#), "shoes 1");
+    record(&storage, request("POST", "/search", r#"{"q":"shoes","page":2}"#), "shoes 2");
+    record(&storage, request("POST", "/search", r#"{"q":"hats","page":2}"#), "hats 2");
+
+    let config = MatchConfig::default();
+
+    assert_eq!(matched_body(&storage, request("POST", "/search", r#"{"q":"shoes","page":2}"#), &config).await.as_deref(), Some("shoes 2"));
+    assert_eq!(matched_body(&storage, request("POST", "/search", r#"{"q":"hats","page":1}"#), &config).await.as_deref(), Some("shoes 1"));
+    assert_eq!(matched_body(&storage, request("POST", "/search", r#"{"q":"hats","page":3}"#), &config).await.as_deref(), Some("hats 2"));
+}
+
+#[tokio::test]
+async fn test_incidental_headers_do_not_outweigh_the_query() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+
+    // Sent by the same client, the recording of another page shares many headers
+    let from_client = |uri: &str| {
+        let mut req = request("GET", uri, "");
+        for (name, value) in [("user-agent", "app/1.0"), ("accept-language", "en"), ("x-client", "web"), ("x-region", "eu")] {
+            req.headers_mut().insert(name, value.parse().unwrap());
+        }
+        req
+    };
+    record(&storage, from_client("/users?page=2"), "page 2");
+    record(&storage, request("GET", "/users?page=1", ""), "page 1");
+
+    let live = from_client("/users?page=1");
+
+    let config = MatchConfig::default();
+    assert_eq!(matched_body(&storage, live, &config).await.as_deref(), Some("page 1"));
+}
+
+#[tokio::test]
+async fn test_dynamic_values_are_normalized() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    record(&storage, request("POST", "/pay", r#"{"amount":5,"nonce":"n-1111"}"#), "paid 5");
+    record(&storage, request("POST", "/pay", r#"{"amount":9,"nonce":"n-2222"}"#), "paid 9");
+
+    let config = MatchConfig { body: true, ..Default::default() };
+    let rules = vec![DynamicValueRule {
+        pattern: Pattern::new(r"n-\d+").unwrap(),
+        generator: "consistent_random".to_string(),
+    }];
+    let dynamic = DynamicValueProcessor::from_rules(&rules, HashMap::new());
+
+    let body = r#"{"amount":9,"nonce":"n-9876"}"#;
+    assert_eq!(matched_body(&storage, request("POST", "/pay", body), &config).await, None);
+    assert_eq!(matched_body_with(&storage, request("POST", "/pay", body), &config, &dynamic).await.as_deref(), Some("paid 9"));
+}
+
+#[test]
+fn test_dynamic_values_are_consistent() {
+    let rules = vec![DynamicValueRule {
+        pattern: Pattern::new(r"req-\w+").unwrap(),
+        generator: "consistent_random".to_string(),
+    }];
+
+    let mut processor = DynamicValueProcessor::from_rules(&rules, HashMap::new());
+    let first = processor.process_request(r#"{"id":"req-abc"}"#);
+    assert!(!first.contains("req-abc"));
+
+    // Values generated earlier are reused by a new processor
+    let mut processor = DynamicValueProcessor::from_rules(&rules, processor.into_values());
+    assert_eq!(processor.process_request(r#"{"id":"req-abc"}"#), first);
+}
+
+#[test]
+fn test_dynamic_value_patterns_are_checked_when_loaded() {
+    let rule = serde_json::json!({ "pattern": r"req-\w+", "generator": "increment" });
+    let rule: DynamicValueRule = serde_json::from_value(rule).unwrap();
+    assert_eq!(rule.pattern.as_str(), r"req-\w+");
+
+    let broken = serde_json::json!({ "pattern": "req-(", "generator": "increment" });
+    assert!(serde_json::from_value::<DynamicValueRule>(broken).is_err());
+}
@@ -0,0 +1,270 @@
+use api_simulator::session::{Delay, PercentilePoint, SessionConfig, SessionId, SessionManager, SessionMode, DEFAULT_SESSION};
+use api_simulator::storage::{
+    FileSystemStorage, FrameDirection, FrameKind, MemoryStorage, Storage, StoredEvent, StoredFrame, StoredInteraction,
+    StoredLatency, StoredStub,
+};
+use axum::body::Bytes;
+use axum::extract::Request;
+use axum::response::Response;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_idle_sessions_expire_unless_pinned() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let manager = SessionManager::new(storage.clone(), None).unwrap();
+
+    manager.create_session("throwaway".to_string()).await.unwrap();
+    manager.create_session("kept".to_string()).await.unwrap();
+    manager.update_session_config("kept", |config| {
+        config.mode = SessionMode::Replay;
+        Ok(())
+    }).await.unwrap();
+    manager.create_session("pinned".to_string()).await.unwrap();
+    manager.update_session_config("pinned", |config| {
+        config.pinned = true;
+        Ok(())
+    }).await.unwrap();
+
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("throwaway", &request, &response).unwrap();
+    storage.store_interaction("kept", &request, &response).unwrap();
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut expired = manager.expire_idle_sessions(Duration::from_millis(10), false).await;
+    expired.sort();
+    assert_eq!(expired, vec!["kept", "throwaway"]);
+
+    let mut remaining = manager.list_sessions();
+    remaining.sort();
+    assert_eq!(remaining, vec![DEFAULT_SESSION, "pinned"]);
+
+    // Recordings stay unless cleanup is asked for
+    assert_eq!(storage.list_interactions("kept").unwrap().len(), 1);
+
+    // The next request brings the session back as it was, not with defaults
+    assert!(manager.restore_session("kept").await.unwrap());
+    assert_eq!(manager.get_session_config("kept").await.unwrap().mode, SessionMode::Replay);
+
+    // Still persisted, the session can only come back as it was
+    assert!(manager.create_session("throwaway".to_string()).await.is_err());
+    storage.store_interaction(DEFAULT_SESSION, &request, &response).unwrap();
+    assert!(manager.clone_session(DEFAULT_SESSION, "throwaway".to_string(), None).await.is_err());
+    assert_eq!(storage.list_interactions("throwaway").unwrap().len(), 1);
+    assert!(manager.restore_session("throwaway").await.unwrap());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    manager.expire_idle_sessions(Duration::from_millis(10), true).await;
+    assert!(storage.list_interactions("throwaway").unwrap().is_empty());
+    assert!(storage.list_sessions().unwrap().iter().all(|(id, _)| id != "throwaway"));
+    assert!(!manager.restore_session("throwaway").await.unwrap());
+}
+
+// Memory storage taking its time to clear interactions
+struct SlowCleanup(MemoryStorage);
+
+impl Storage for SlowCleanup {
+    fn store_interaction(&self, session_id: &str, request: &Request<Bytes>, response: &Response<Bytes>) -> Result<(), String> {
+        self.0.store_interaction(session_id, request, response)
+    }
+
+    fn insert_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        self.0.insert_interaction(session_id, interaction)
+    }
+
+    fn list_interactions(&self, session_id: &str) -> Result<Vec<StoredInteraction>, String> {
+        self.0.list_interactions(session_id)
+    }
+
+    fn get_interaction(&self, session_id: &str, interaction_id: &str) -> Result<Option<StoredInteraction>, String> {
+        self.0.get_interaction(session_id, interaction_id)
+    }
+
+    fn update_interaction(&self, session_id: &str, interaction: &StoredInteraction) -> Result<(), String> {
+        self.0.update_interaction(session_id, interaction)
+    }
+
+    fn delete_interaction(&self, session_id: &str, interaction_id: &str) -> Result<(), String> {
+        self.0.delete_interaction(session_id, interaction_id)
+    }
+
+    fn clear_interactions(&self, session_id: &str) -> Result<(), String> {
+        std::thread::sleep(Duration::from_millis(200));
+        self.0.clear_interactions(session_id)
+    }
+
+    fn store_session(&self, session_id: &str, config: &SessionConfig) -> Result<(), String> {
+        self.0.store_session(session_id, config)
+    }
+
+    fn get_session(&self, session_id: &str) -> Result<Option<SessionConfig>, String> {
+        self.0.get_session(session_id)
+    }
+
+    fn list_sessions(&self) -> Result<Vec<(SessionId, Option<SessionConfig>)>, String> {
+        self.0.list_sessions()
+    }
+
+    fn delete_session(&self, session_id: &str) -> Result<(), String> {
+        self.0.delete_session(session_id)
+    }
+
+    fn store_stub(&self, session_id: &str, stub: &StoredStub) -> Result<(), String> {
+        self.0.store_stub(session_id, stub)
+    }
+
+    fn list_stubs(&self, session_id: &str) -> Result<Vec<StoredStub>, String> {
+        self.0.list_stubs(session_id)
+    }
+
+    fn delete_stub(&self, session_id: &str, stub_id: &str) -> Result<(), String> {
+        self.0.delete_stub(session_id, stub_id)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_sessions_are_not_restored_while_their_storage_is_removed() {
+    let storage: Arc<dyn Storage> = Arc::new(SlowCleanup(MemoryStorage::new()));
+    let manager = Arc::new(SessionManager::new(storage.clone(), None).unwrap());
+
+    manager.create_session("evicted".to_string()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let expiring = tokio::spawn({
+        let manager = manager.clone();
+        async move { manager.expire_idle_sessions(Duration::from_millis(10), true).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Asked for halfway through the cleanup, the session is gone by the time it is looked up
+    assert!(!manager.restore_session("evicted").await.unwrap());
+    assert_eq!(expiring.await.unwrap(), vec!["evicted"]);
+    assert!(!manager.session_exists("evicted").await);
+
+    // Sessions created again get storage that stays
+    manager.create_session("evicted".to_string()).await.unwrap();
+    assert!(storage.get_session("evicted").unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_session_ids_cannot_leave_the_storage_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let recordings = dir.path().join("recordings");
+    let storage: Arc<dyn Storage> = Arc::new(FileSystemStorage::new(recordings.to_str().unwrap()).unwrap());
+    let manager = SessionManager::new(storage, None).unwrap();
+
+    for id in ["..", ".", "", "a/b", "../b", "/tmp", "a\\b"] {
+        assert!(manager.create_session(id.to_string()).await.is_err(), "{} was accepted", id);
+        assert!(manager.clone_session(DEFAULT_SESSION, id.to_string(), None).await.is_err(), "{} was cloned", id);
+    }
+    assert!(!dir.path().join("session.json").exists());
+    assert!(!dir.path().join("b").exists());
+
+    // Nothing outside the storage can be evicted
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(manager.expire_idle_sessions(Duration::from_millis(10), true).await.is_empty());
+    assert!(recordings.join(DEFAULT_SESSION).join("session.json").exists());
+
+    manager.create_session("plain-id_1.v2".to_string()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_corrupt_persisted_session_is_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap());
+
+    let manager = SessionManager::new(storage.clone(), None).unwrap();
+    manager.create_session("kept".to_string()).await.unwrap();
+    drop(manager);
+
+    std::fs::create_dir(dir.path().join("stale")).unwrap();
+    std::fs::write(dir.path().join("stale").join("session.json"), "{ not json").unwrap();
+
+    let manager = SessionManager::new(storage, None).unwrap();
+    assert!(manager.session_exists("kept").await);
+    assert!(!manager.session_exists("stale").await);
+}
+
+#[tokio::test]
+async fn test_sessions_without_a_persisted_configuration_are_listed() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap());
+
+    // Recorded before configurations were persisted, next to a stray file
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("legacy", &request, &response).unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "not a session").unwrap();
+
+    let manager = SessionManager::new(storage.clone(), None).unwrap();
+    let mut sessions = manager.list_sessions();
+    sessions.sort();
+    assert_eq!(sessions, vec![DEFAULT_SESSION, "legacy"]);
+    assert_eq!(manager.list_interactions("legacy").await.unwrap().len(), 1);
+
+    // The defaults it came back with are kept
+    assert_eq!(storage.get_session("legacy").unwrap().unwrap().mode, SessionConfig::default().mode);
+}
+
+#[tokio::test]
+async fn test_clone_keeps_recorded_events_frames_and_latency() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let manager = SessionManager::new(storage.clone(), None).unwrap();
+
+    let request = Request::builder().uri("/events").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::from_static(b"data: a\n\n")).unwrap();
+    storage.store_interaction(DEFAULT_SESSION, &request, &response).unwrap();
+
+    let mut recorded = storage.list_interactions(DEFAULT_SESSION).unwrap().remove(0);
+    recorded.response.events = vec![StoredEvent { offset_ms: 250, data: "data: a\n\n".to_string() }];
+    recorded.response.frames = vec![StoredFrame {
+        offset_ms: 10,
+        direction: FrameDirection::Server,
+        kind: FrameKind::Text,
+        payload: b"hi".to_vec(),
+    }];
+    recorded.response.latency = Some(StoredLatency { ttfb_ms: 120, total_ms: 400 });
+    storage.update_interaction(DEFAULT_SESSION, &recorded).unwrap();
+
+    manager.clone_session(DEFAULT_SESSION, "copy".to_string(), None).await.unwrap();
+
+    let copied = storage.list_interactions("copy").unwrap().remove(0);
+    assert_eq!(copied.response.events, recorded.response.events);
+    assert_eq!(copied.response.frames, recorded.response.frames);
+    assert_eq!(copied.response.latency, recorded.response.latency);
+}
+
+#[tokio::test]
+async fn test_interactions_of_unknown_sessions_are_left_alone() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let manager = SessionManager::new(storage.clone(), None).unwrap();
+
+    // Recorded under an ID the manager does not know about
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("unknown", &request, &response).unwrap();
+    let interaction = storage.list_interactions("unknown").unwrap().remove(0);
+
+    assert!(manager.update_interaction("unknown", interaction.clone()).await.is_err());
+    assert!(manager.delete_interaction("unknown", &interaction.id).await.is_err());
+    assert_eq!(storage.list_interactions("unknown").unwrap().len(), 1);
+}
+
+#[test]
+fn test_percentile_delays_follow_their_distribution() {
+    let delay: Delay = serde_json::from_value(serde_json::json!({
+        "type": "percentiles",
+        "points": [{ "percentile": 99, "ms": 1000 }, { "percentile": 50, "ms": 100 }],
+    })).unwrap();
+    delay.validate().unwrap();
+
+    let samples: Vec<Duration> = (0..2000).map(|_| delay.sample()).collect();
+    assert!(samples.iter().all(|sample| *sample <= Duration::from_millis(1000)));
+
+    let below_median = samples.iter().filter(|sample| **sample <= Duration::from_millis(100)).count();
+    assert!((800..1200).contains(&below_median));
+
+    let invalid = Delay::Percentiles { points: vec![PercentilePoint { percentile: 150.0, ms: 10 }] };
+    assert!(invalid.validate().is_err());
+}
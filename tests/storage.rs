@@ -0,0 +1,198 @@
+use api_simulator::storage::{
+    FileSystemStorage, MemoryStorage, SpillBuffer, Storage, StoredRequest, StoredStub, StubRequest,
+};
+use axum::body::Bytes;
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::response::Response;
+
+#[test]
+fn test_headers_round_trip() {
+    let storage = MemoryStorage::new().with_header_deny_list(vec!["Authorization".to_string()]);
+
+    let mut request = Request::builder()
+        .method("GET")
+        .uri("/users?page=1")
+        .header("Authorization", "Bearer secret")
+        .header("Accept", "application/json")
+        .body(Bytes::new())
+        .unwrap();
+    request.headers_mut().insert("x-raw", HeaderValue::from_bytes(&[0x66, 0xff, 0x6f]).unwrap());
+
+    let response = Response::builder()
+        .status(201)
+        .header("Content-Type", "application/json")
+        .header("Set-Cookie", "a=1")
+        .header("Set-Cookie", "b=2")
+        .body(Bytes::from_static(b"{}"))
+        .unwrap();
+
+    storage.store_interaction("test", &request, &response).unwrap();
+
+    let interactions = storage.get_interactions("test").unwrap();
+    assert_eq!(interactions.len(), 1);
+
+    let (stored_req, stored_resp) = &interactions[0];
+    assert!(stored_req.headers().get("authorization").is_none());
+    assert_eq!(stored_req.headers()["accept"], "application/json");
+    assert_eq!(stored_req.headers()["x-raw"].as_bytes(), &[0x66, 0xff, 0x6f]);
+
+    assert_eq!(stored_resp.headers()["content-type"], "application/json");
+    let cookies: Vec<_> = stored_resp.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(cookies, vec!["a=1", "b=2"]);
+}
+
+#[test]
+fn test_legacy_header_map_is_readable() {
+    let json = r#"{
+        "method": "GET",
+        "uri": "/users",
+        "headers": {"accept": ["application/json"]},
+        "body": []
+    }"#;
+
+    let stored: StoredRequest = serde_json::from_str(json).unwrap();
+    assert_eq!(stored.headers.get("Accept").unwrap().as_bytes(), b"application/json");
+}
+
+#[test]
+fn test_filesystem_stubs_kept_apart_from_recordings() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap();
+
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("test", &request, &response).unwrap();
+
+    let low = StoredStub::new(0, StubRequest::default(), serde_json::from_str("{}").unwrap());
+    let high = StoredStub::new(5, StubRequest::default(), serde_json::from_str("{}").unwrap());
+    storage.store_stub("test", &low).unwrap();
+    storage.store_stub("test", &high).unwrap();
+
+    assert_eq!(storage.list_interactions("test").unwrap().len(), 1);
+
+    let stubs = storage.list_stubs("test").unwrap();
+    let ids: Vec<_> = stubs.iter().map(|stub| stub.id.as_str()).collect();
+    assert_eq!(ids, vec![high.id.as_str(), low.id.as_str()]);
+    assert_eq!(stubs[1].response.status, 200);
+
+    storage.delete_stub("test", &low.id).unwrap();
+    assert!(storage.delete_stub("test", &low.id).is_err());
+    assert_eq!(storage.list_stubs("test").unwrap().len(), 1);
+}
+
+#[test]
+fn test_filesystem_rejects_stub_ids_naming_other_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap();
+
+    // Two levels up from the stubs directory of the session
+    let victim = dir.path().join("victim.json");
+    std::fs::write(&victim, "{}").unwrap();
+
+    assert!(storage.delete_stub("test", "../../victim").is_err());
+    assert!(victim.exists());
+
+    let mut stub = StoredStub::new(0, StubRequest::default(), serde_json::from_str("{}").unwrap());
+    stub.id = "../../victim".to_string();
+    assert!(storage.store_stub("test", &stub).is_err());
+    assert_eq!(std::fs::read_to_string(&victim).unwrap(), "{}");
+}
+
+#[test]
+fn test_filesystem_interaction_by_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap();
+
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::from_static(b"old")).unwrap();
+    storage.store_interaction("test", &request, &response).unwrap();
+
+    let mut interaction = storage.list_interactions("test").unwrap().remove(0);
+    interaction.response.body = b"new".to_vec();
+    storage.update_interaction("test", &interaction).unwrap();
+
+    let stored = storage.get_interaction("test", &interaction.id).unwrap().unwrap();
+    assert_eq!(stored.response.body, b"new");
+
+    storage.delete_interaction("test", &interaction.id).unwrap();
+    assert!(storage.get_interaction("test", &interaction.id).unwrap().is_none());
+    assert!(storage.update_interaction("test", &interaction).is_err());
+}
+
+#[test]
+fn test_filesystem_rejects_interaction_ids_naming_other_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FileSystemStorage::new(dir.path().to_str().unwrap()).unwrap();
+
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("test", &request, &response).unwrap();
+
+    // One level up from the session directory
+    let victim = dir.path().join("victim.json");
+    std::fs::write(&victim, "{}").unwrap();
+
+    assert!(storage.get_interaction("test", "../victim").is_err());
+    assert!(storage.delete_interaction("test", "../victim").is_err());
+    assert!(victim.exists());
+
+    let mut interaction = storage.list_interactions("test").unwrap().remove(0);
+    interaction.id = "../victim".to_string();
+    assert!(storage.update_interaction("test", &interaction).is_err());
+    assert_eq!(std::fs::read_to_string(&victim).unwrap(), "{}");
+
+    // The session configuration is not an interaction either
+    storage.store_session("test", &Default::default()).unwrap();
+    assert!(storage.get_interaction("test", "session").is_err());
+}
+
+#[test]
+fn test_memory_rejects_the_ids_the_filesystem_rejects() {
+    let storage = MemoryStorage::new();
+
+    let request = Request::builder().uri("/users").body(Bytes::new()).unwrap();
+    let response = Response::builder().body(Bytes::new()).unwrap();
+    storage.store_interaction("test", &request, &response).unwrap();
+
+    assert!(storage.get_interaction("test", "../victim").is_err());
+    assert!(storage.delete_interaction("test", "../victim").is_err());
+
+    let mut interaction = storage.list_interactions("test").unwrap().remove(0);
+    interaction.id = "../victim".to_string();
+    assert!(storage.update_interaction("test", &interaction).is_err());
+    assert!(storage.insert_interaction("test", &interaction).is_err());
+
+    let mut stub = StoredStub::new(0, StubRequest::default(), serde_json::from_str("{}").unwrap());
+    stub.id = "../../victim".to_string();
+    assert!(storage.store_stub("test", &stub).is_err());
+    assert!(storage.delete_stub("test", "../../victim").is_err());
+
+    assert!(storage.store_session("../outside", &Default::default()).is_err());
+    assert!(storage.get_session("..").is_err());
+}
+
+#[test]
+fn test_filesystem_sessions_stay_in_their_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = FileSystemStorage::new(dir.path().join("recordings").to_str().unwrap()).unwrap();
+
+    assert!(storage.store_session("..", &Default::default()).is_err());
+    assert!(storage.store_session("../outside", &Default::default()).is_err());
+    assert!(!dir.path().join("session.json").exists());
+    assert!(!dir.path().join("outside").exists());
+}
+
+#[test]
+fn test_spill_buffer_moves_to_file_past_threshold() {
+    let mut buffer = SpillBuffer::new(8);
+
+    buffer.write(b"hello").unwrap();
+    assert!(!buffer.is_spilled());
+
+    buffer.write(b" world").unwrap();
+    assert!(buffer.is_spilled());
+    assert_eq!(buffer.len(), 11);
+
+    assert_eq!(&buffer.into_bytes().unwrap()[..], b"hello world");
+}
@@ -0,0 +1,55 @@
+use api_simulator::template::ResponseTemplater;
+use axum::body::Bytes;
+use axum::extract::Request;
+use axum::response::Response;
+
+fn render(template: &str, req: &Request<Bytes>) -> String {
+    let response = Response::builder()
+        .header("X-Correlation-Id", "{{request.headers.x-correlation-id}}")
+        .body(Bytes::from(template.to_string()))
+        .unwrap();
+
+    let rendered = ResponseTemplater::new().render_response(response, req).unwrap();
+    String::from_utf8(rendered.body().to_vec()).unwrap()
+}
+
+#[test]
+fn test_request_references() {
+    let req = Request::builder()
+        .method("POST")
+        .uri("/shops/7/orders?currency=EUR")
+        .header("X-Correlation-Id", "corr-1")
+        .body(Bytes::from_static(br#"{"orderId":"o-42","lines":[{"sku":"a"}]}"#))
+        .unwrap();
+
+    assert_eq!(
+        render(r#"{"id":"{{request.body.orderId}}","shop":"{{request.path.[1]}}"}"#, &req),
+        r#"{"id":"o-42","shop":"7"}"#
+    );
+    assert_eq!(render("{{request.query.currency}} {{request.body.lines.[0].sku}}", &req), "EUR a");
+    assert_eq!(render("{{request.method}} {{request.url}}", &req), "POST /shops/7/orders?currency=EUR");
+
+    let response = Response::builder()
+        .header("X-Correlation-Id", "{{request.headers.x-correlation-id}}")
+        .header("Content-Length", "2")
+        .body(Bytes::from_static(b"{{request.body.orderId}}"))
+        .unwrap();
+    let rendered = ResponseTemplater::new().render_response(response, &req).unwrap();
+    assert_eq!(rendered.headers()["x-correlation-id"], "corr-1");
+    assert!(rendered.headers().get("content-length").is_none());
+}
+
+#[test]
+fn test_helpers() {
+    let req = Request::builder()
+        .uri("/")
+        .body(Bytes::new())
+        .unwrap();
+
+    assert_eq!(render("{{base64 \"hello\"}}", &req), "aGVsbG8=");
+    assert_eq!(render("{{base64 \"aGVsbG8=\" decode=true}}", &req), "hello");
+    assert_eq!(render("{{uuid}}", &req).len(), 36);
+    assert_eq!(render("{{randomInt 3 3}}", &req), "3");
+    assert!(render("{{now \"epoch\"}}", &req).parse::<u64>().is_ok());
+    assert!(render("{{now}}", &req).ends_with('Z'));
+}
@@ -0,0 +1,163 @@
+use api_simulator::config::{AppConfig, ProxyConfig, TlsConfig, TlsRootStore};
+use api_simulator::session::SessionMode;
+use api_simulator::upstream::{build_connector, build_tls_config};
+
+mod common;
+
+use common::spawn_app;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::sync::Arc;
+
+#[test]
+fn test_webpki_root_store() {
+    let config = TlsConfig {
+        root_store: TlsRootStore::Webpki,
+        ..Default::default()
+    };
+
+    assert!(build_tls_config(&config).is_ok());
+}
+
+#[test]
+fn test_custom_root_store_requires_bundle() {
+    let config = TlsConfig {
+        root_store: TlsRootStore::Custom,
+        ca_bundle_path: None,
+        insecure_skip_verify: false,
+    };
+
+    let err = build_tls_config(&config).unwrap_err();
+    assert!(err.contains("ca_bundle_path"));
+}
+
+#[test]
+fn test_custom_root_store_rejects_empty_bundle() {
+    let bundle = tempfile::NamedTempFile::new().unwrap();
+
+    let config = TlsConfig {
+        root_store: TlsRootStore::Custom,
+        ca_bundle_path: Some(bundle.path().to_string_lossy().to_string()),
+        insecure_skip_verify: false,
+    };
+
+    assert!(build_tls_config(&config).is_err());
+}
+
+#[test]
+fn test_insecure_skip_verify_ignores_root_store() {
+    let config = ProxyConfig {
+        tls: TlsConfig {
+            root_store: TlsRootStore::Custom,
+            ca_bundle_path: None,
+            insecure_skip_verify: true,
+        },
+        ..Default::default()
+    };
+
+    assert!(build_connector(&config).is_ok());
+}
+
+// Serve HTTPS on a free port with a certificate for localhost, returning the
+// port and the certificate as PEM
+async fn spawn_tls_upstream() -> (u16, String) {
+    use axum::{extract::Request, routing::any, Router};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::service::TowerToHyperService;
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+
+    let app = Router::new().fallback(any(|req: Request| async move {
+        let host = req.headers().get("host").and_then(|value| value.to_str().ok()).unwrap_or_default();
+        format!("upstream saw {} for {} over TLS", req.uri(), host)
+    }));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (acceptor, app) = (acceptor.clone(), app.clone());
+            tokio::spawn(async move {
+                // Handshakes the simulator refuses fail here, they are not errors of the test
+                let Ok(stream) = acceptor.accept(stream).await else { return };
+                let _ = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app))
+                    .await;
+            });
+        }
+    });
+
+    (port, certified.cert.pem())
+}
+
+// Send a request through a passthrough simulator with the given TLS settings,
+// returning the response and the simulator's address
+async fn get_through_simulator(tls: TlsConfig, target: &str, forward_host_header: bool) -> (reqwest::Response, String) {
+    let config = AppConfig {
+        proxy: ProxyConfig {
+            default_mode: SessionMode::Passthrough,
+            default_target: target.to_string(),
+            forward_host_header,
+            tls,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (addr, handle) = spawn_app(config).await;
+
+    let response = reqwest::get(format!("http://{}/users?page=2", addr)).await.unwrap();
+    handle.abort();
+    (response, addr.to_string())
+}
+
+#[tokio::test]
+async fn test_https_upstream() {
+    let (port, cert_pem) = spawn_tls_upstream().await;
+    let target = format!("https://localhost:{}", port);
+
+    let bundle = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(bundle.path(), cert_pem).unwrap();
+
+    // Trusted through a custom bundle holding the upstream certificate
+    let custom = TlsConfig {
+        root_store: TlsRootStore::Custom,
+        ca_bundle_path: Some(bundle.path().to_string_lossy().to_string()),
+        insecure_skip_verify: false,
+    };
+    let (response, _) = get_through_simulator(custom.clone(), &target, false).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), format!("upstream saw /users?page=2 for localhost:{} over TLS", port));
+
+    // The client's Host only goes upstream when asked for
+    let (response, simulator) = get_through_simulator(custom, &target, true).await;
+    assert_eq!(response.text().await.unwrap(), format!("upstream saw /users?page=2 for {} over TLS", simulator));
+
+    // Trusted without verification
+    let insecure = TlsConfig {
+        root_store: TlsRootStore::Webpki,
+        ca_bundle_path: None,
+        insecure_skip_verify: true,
+    };
+    let (response, _) = get_through_simulator(insecure, &target, false).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), format!("upstream saw /users?page=2 for localhost:{} over TLS", port));
+
+    // A self-signed certificate is refused by the public roots
+    let webpki = TlsConfig {
+        root_store: TlsRootStore::Webpki,
+        ..Default::default()
+    };
+    let (response, _) = get_through_simulator(webpki, &target, false).await;
+    assert!(!response.status().is_success());
+}